@@ -0,0 +1,185 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use serde_json::{json, Value};
+
+use crate::numa;
+use crate::parser::args::{AffinityPolicy, CpuArgs};
+use crate::utils;
+
+/// The host CPU of each vCPU, from `--vcpu-affinity` and `--affinity-policy`.
+pub struct VcpuAffinity {
+    /// the host CPUs given by `--vcpu-affinity`
+    pinning: BTreeMap<u8, usize>,
+    /// the host CPUs for the other vCPUs in the order of the policy
+    policy_cpus: Vec<usize>,
+}
+
+impl VcpuAffinity {
+    pub fn new(args: &CpuArgs) -> Result<Option<Self>> {
+        if args.vcpu_affinity.is_none() && args.affinity_policy.is_none() {
+            return Ok(None);
+        }
+        // the CPUs of the cpuset of dbs-cli.
+        let allowed = sched_getaffinity(Pid::from_raw(0))
+            .context("Failed to get the CPU affinity of dbs-cli")?;
+        let allowed_cpus: Vec<usize> = (0..CpuSet::count())
+            .filter(|cpu| allowed.is_set(*cpu).unwrap_or(false))
+            .collect();
+
+        let mut pinning = BTreeMap::new();
+        for &(vcpu, cpu) in args.vcpu_affinity.iter().flat_map(|pinning| &pinning.0) {
+            if vcpu >= args.max_vcpu.max(args.vcpu) {
+                return Err(anyhow!(
+                    "vCPU {vcpu} in --vcpu-affinity is beyond the max vCPUs {}",
+                    args.max_vcpu.max(args.vcpu)
+                ));
+            }
+            if !allowed_cpus.contains(&cpu) {
+                return Err(anyhow!(
+                    "host CPU {cpu} in --vcpu-affinity is not one dbs-cli may run on"
+                ));
+            }
+            if pinning.insert(vcpu, cpu).is_some() {
+                return Err(anyhow!("vCPU {vcpu} is pinned more than once"));
+            }
+        }
+
+        let policy_cpus = match args.affinity_policy {
+            Some(policy) => {
+                let nodes = numa::host_nodes()?
+                    .into_iter()
+                    .map(|(_, cpus)| {
+                        cpus.into_iter()
+                            .filter(|cpu| allowed_cpus.contains(cpu))
+                            .filter(|cpu| !pinning.values().any(|pinned| pinned == cpu))
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|cpus| !cpus.is_empty())
+                    .collect::<Vec<_>>();
+                order_cpus(policy, nodes)
+            }
+            None => vec![],
+        };
+        if args.affinity_policy.is_some() && policy_cpus.is_empty() {
+            return Err(anyhow!(
+                "no host CPU is left for --affinity-policy besides --vcpu-affinity"
+            ));
+        }
+        // the vCPUs beyond the CPUs of the policy share them.
+        let policy_vcpus = (0..args.max_vcpu.max(args.vcpu))
+            .filter(|vcpu| !pinning.contains_key(vcpu))
+            .count();
+        if args.affinity_policy.is_some() && policy_vcpus > policy_cpus.len() {
+            println!(
+                "Warning: {} vCPUs share {} host CPUs of --affinity-policy, some of them are pinned to the same CPU.",
+                policy_vcpus,
+                policy_cpus.len()
+            );
+        }
+
+        Ok(Some(VcpuAffinity {
+            pinning,
+            policy_cpus,
+        }))
+    }
+
+    /// The host CPU of `vcpu`, None to leave it unpinned.
+    fn host_cpu(&self, vcpu: u8) -> Option<usize> {
+        if let Some(cpu) = self.pinning.get(&vcpu) {
+            return Some(*cpu);
+        }
+        if self.policy_cpus.is_empty() {
+            return None;
+        }
+        // the vCPUs out of --vcpu-affinity take the CPUs of the policy in
+        // turn, sharing them when there are more vCPUs, as warned in new().
+        let rank = (0..vcpu)
+            .filter(|vcpu| !self.pinning.contains_key(vcpu))
+            .count();
+        Some(self.policy_cpus[rank % self.policy_cpus.len()])
+    }
+
+    /// Pin the vCPU threads, once `vcpu_count` vCPUs are running, which is
+    /// done again for the vCPUs added by `resize_vcpu`.
+    pub fn apply(&self, vcpu_count: usize) {
+        for (vcpu, tid) in utils::wait_vcpu_threads(vcpu_count) {
+            let cpu = match u8::try_from(vcpu).ok().and_then(|vcpu| self.host_cpu(vcpu)) {
+                Some(cpu) => cpu,
+                None => continue,
+            };
+            let mut cpu_set = CpuSet::new();
+            let result = cpu_set
+                .set(cpu)
+                .and_then(|_| sched_setaffinity(Pid::from_raw(tid), &cpu_set));
+            if let Err(err) = result {
+                log::warn!("Failed to pin vCPU {} to host CPU {}: {:?}", vcpu, cpu, err);
+            }
+        }
+    }
+}
+
+/// Order the CPUs of the NUMA nodes for a policy.
+fn order_cpus(policy: AffinityPolicy, nodes: Vec<Vec<usize>>) -> Vec<usize> {
+    match policy {
+        AffinityPolicy::Pack => nodes.into_iter().flatten().collect(),
+        AffinityPolicy::Spread => {
+            let max_len = nodes.iter().map(Vec::len).max().unwrap_or(0);
+            (0..max_len)
+                .flat_map(|i| nodes.iter().filter_map(move |cpus| cpus.get(i).copied()))
+                .collect()
+        }
+    }
+}
+
+/// The host CPUs each vCPU thread may run on, for the `get_vcpu_affinity`
+/// query.
+pub fn query_vcpu_affinity() -> Value {
+    let vcpus: Vec<Value> = utils::vcpu_threads()
+        .into_iter()
+        .map(|(vcpu, tid)| {
+            let cpus: Vec<usize> = match sched_getaffinity(Pid::from_raw(tid)) {
+                Ok(cpu_set) => (0..CpuSet::count())
+                    .filter(|cpu| cpu_set.is_set(*cpu).unwrap_or(false))
+                    .collect(),
+                Err(_) => vec![],
+            };
+            json!({ "vcpu": vcpu, "tid": tid, "cpus": cpus })
+        })
+        .collect();
+    json!({ "vcpus": vcpus })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_cpus() {
+        let nodes = vec![vec![0, 1, 2], vec![4, 5], vec![]];
+        assert_eq!(
+            order_cpus(AffinityPolicy::Pack, nodes.clone()),
+            vec![0, 1, 2, 4, 5]
+        );
+        assert_eq!(
+            order_cpus(AffinityPolicy::Spread, nodes),
+            vec![0, 4, 1, 5, 2]
+        );
+        assert!(order_cpus(AffinityPolicy::Spread, vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_host_cpu() {
+        let affinity = VcpuAffinity {
+            pinning: BTreeMap::from([(1, 7)]),
+            policy_cpus: vec![2, 3],
+        };
+        let cpus: Vec<_> = (0..5).map(|vcpu| affinity.host_cpu(vcpu)).collect();
+        assert_eq!(cpus, vec![Some(2), Some(7), Some(3), Some(2), Some(3)]);
+    }
+}
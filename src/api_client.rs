@@ -1,27 +1,55 @@
 // Copyright (C) 2022 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::Write;
+use std::io::{Read, Write};
+use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
 
 use anyhow::{Context, Result};
 use serde_json::{json, Value};
 
-use crate::parser::args::{HostDeviceArgs, UpdateArgs};
+use crate::cpu_topology;
+use crate::parser::args::{GetArgs, HostDeviceArgs, UpdateArgs};
 
 pub fn run_api_client(args: UpdateArgs, api_sock_path: &str) -> Result<()> {
     if let Some(vcpu_resize_num) = args.vcpu_resize {
+        // requests get no response, so an invalid count is reported here.
+        let response = send_query(request_vm_info(), api_sock_path)?;
+        let max_vcpu_count = response["max_vcpu_count"].as_u64().unwrap_or_default();
+        cpu_topology::check_vcpu_resize(
+            Some(vcpu_resize_num as u64),
+            max_vcpu_count.try_into().unwrap_or(u8::MAX),
+        )?;
         let request = request_cpu_resize(vcpu_resize_num);
         send_request(request, api_sock_path)?;
     }
 
+    // the limits follow the resized vCPUs, as the requests are handled in order.
+    if args.cgroup_cpu_max.is_some()
+        || args.cgroup_cpuset.is_some()
+        || args.cgroup_memory_max.is_some()
+    {
+        let request = request_update_cgroup(&args);
+        send_request(request, api_sock_path)?;
+    }
+
     if let Some(config) = args.virnets {
         let request = request_virtio_net(&config);
         send_request(request, api_sock_path)?;
     }
 
     if let Some(config) = args.virblks {
-        let request = request_virtio_blk(&config);
+        let request = request_virtio_blk(&config, &args.drive_sha256);
+        send_request(request, api_sock_path)?;
+    }
+
+    if let Some(config) = args.vhost_user_blks {
+        let request = request_vhost_user_blk(&config);
+        send_request(request, api_sock_path)?;
+    }
+
+    if let Some(config) = args.vhost_user_fs {
+        let request = request_vhost_user_fs(&config);
         send_request(request, api_sock_path)?;
     }
 
@@ -49,6 +77,131 @@ pub fn run_api_client(args: UpdateArgs, api_sock_path: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn run_get_client(args: GetArgs, api_sock_path: &str) -> Result<()> {
+    if args.info {
+        let response = send_query(request_vm_info(), api_sock_path)?;
+        println!(
+            "vm_id: {}, vcpus: {}/{}, memory: {} MiB",
+            response["vm_id"].as_str().unwrap_or_default(),
+            response["vcpu_count"],
+            response["max_vcpu_count"],
+            response["mem_size_mib"]
+        );
+        if let Some(cgroup) = response["cgroup"].as_str() {
+            println!("cgroup: {cgroup}");
+        }
+    }
+
+    if args.vcpu_affinity {
+        let response = send_query(request_vcpu_affinity(), api_sock_path)?;
+        for vcpu in response["vcpus"].as_array().into_iter().flatten() {
+            let cpus: Vec<String> = vcpu["cpus"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|cpu| cpu.to_string())
+                .collect();
+            println!(
+                "vcpu {} (tid {}): {}",
+                vcpu["vcpu"],
+                vcpu["tid"],
+                cpus.join(",")
+            );
+        }
+    }
+
+    if args.cpuid {
+        let response = send_query(request_cpuid(), api_sock_path)?;
+        let names = |key: &str| -> Vec<String> {
+            response[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect()
+        };
+        println!(
+            "clearcpuid level: {}",
+            response["clearcpuid_level"].as_str().unwrap_or_default()
+        );
+        println!(
+            "guest kernel features: {}",
+            names("kernel_features").join(",")
+        );
+        println!("hidden with clearcpuid=: {}", names("clearcpuid").join(","));
+        println!("CPUID supported by the host, as guest applications read it:");
+        for leaf in response["host_leaves"].as_array().into_iter().flatten() {
+            println!(
+                "leaf {} subleaf {}: eax={} ebx={} ecx={} edx={}",
+                leaf["leaf"].as_str().unwrap_or_default(),
+                leaf["subleaf"],
+                leaf["eax"].as_str().unwrap_or_default(),
+                leaf["ebx"].as_str().unwrap_or_default(),
+                leaf["ecx"].as_str().unwrap_or_default(),
+                leaf["edx"].as_str().unwrap_or_default()
+            );
+        }
+    }
+
+    if args.digests {
+        let response = send_query(request_digests(), api_sock_path)?;
+        for artifact in response["artifacts"].as_array().into_iter().flatten() {
+            println!(
+                "{} {}: sha256 {}{}",
+                artifact["artifact"].as_str().unwrap_or_default(),
+                artifact["path"].as_str().unwrap_or_default(),
+                artifact["sha256"].as_str().unwrap_or_default(),
+                if artifact["verified"].as_bool().unwrap_or_default() {
+                    " (verified)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    if args.console_log {
+        let request = request_console_log(args.console_log_size_kb);
+        let response = send_query(request, api_sock_path)?;
+        print!("{}", response["console_log"].as_str().unwrap_or_default());
+    }
+    Ok(())
+}
+
+pub fn run_shutdown_client(api_sock_path: &str) -> Result<()> {
+    send_request(request_shutdown(), api_sock_path)
+}
+
+fn request_vm_info() -> Value {
+    json!({
+        "action": "get_vm_info",
+    })
+}
+
+fn request_vcpu_affinity() -> Value {
+    json!({
+        "action": "get_vcpu_affinity",
+    })
+}
+
+fn request_digests() -> Value {
+    json!({
+        "action": "get_digests",
+    })
+}
+
+fn request_cpuid() -> Value {
+    json!({
+        "action": "get_cpuid",
+    })
+}
+
+fn request_shutdown() -> Value {
+    json!({
+        "action": "shutdown",
+    })
+}
+
 fn request_cpu_resize(vcpu_resize_num: usize) -> Value {
     json!({
         "action": "resize_vcpu",
@@ -56,6 +209,15 @@ fn request_cpu_resize(vcpu_resize_num: usize) -> Value {
     })
 }
 
+fn request_update_cgroup(args: &UpdateArgs) -> Value {
+    json!({
+        "action": "update_cgroup",
+        "cpu_max": args.cgroup_cpu_max,
+        "cpuset": args.cgroup_cpuset,
+        "memory_max": args.cgroup_memory_max,
+    })
+}
+
 /// Insert virtio network devices
 fn request_virtio_net(net_config: &str) -> Value {
     json!({
@@ -64,11 +226,29 @@ fn request_virtio_net(net_config: &str) -> Value {
     })
 }
 
-/// Insert virtio-blk devices
-fn request_virtio_blk(virtio_blk_config: &str) -> Value {
+/// Insert virtio-blk devices, the ones in `drive_sha256` only if they match
+/// their digests.
+fn request_virtio_blk(virtio_blk_config: &str, drive_sha256: &[(String, String)]) -> Value {
     json!({
         "action": "insert_virblks",
         "config": virtio_blk_config,
+        "drive_sha256": drive_sha256,
+    })
+}
+
+/// Insert vhost-user-blk devices
+fn request_vhost_user_blk(vhost_user_blk_config: &str) -> Value {
+    json!({
+        "action": "insert_vhost_user_blks",
+        "config": vhost_user_blk_config,
+    })
+}
+
+/// Insert a vhost-user-fs device
+fn request_vhost_user_fs(vhost_user_fs_config: &str) -> Value {
+    json!({
+        "action": "insert_vhost_user_fs",
+        "config": vhost_user_fs_config,
     })
 }
 
@@ -101,7 +281,14 @@ fn request_remove_host_device(host_device_id: String) -> Value {
     })
 }
 
-fn send_request(request: Value, api_sock_path: &str) -> Result<()> {
+fn request_console_log(size_kb: Option<usize>) -> Value {
+    json!({
+        "action": "get_console_log",
+        "size_kb": size_kb,
+    })
+}
+
+pub(crate) fn send_request(request: Value, api_sock_path: &str) -> Result<()> {
     let mut unix_stream = UnixStream::connect(api_sock_path).context("Could not create stream")?;
 
     unix_stream
@@ -110,3 +297,20 @@ fn send_request(request: Value, api_sock_path: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Send a query and wait for its response.
+pub(crate) fn send_query(request: Value, api_sock_path: &str) -> Result<Value> {
+    let mut unix_stream = UnixStream::connect(api_sock_path).context("Could not create stream")?;
+
+    unix_stream
+        .write_all(request.to_string().as_bytes())
+        .context("Failed at writing onto the unix stream")?;
+    // the server reads the request until EOF
+    unix_stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    unix_stream
+        .read_to_string(&mut response)
+        .context("Failed at reading the response from the unix stream")?;
+    serde_json::from_str(&response).context("Failed to parse the response")
+}
@@ -9,21 +9,48 @@ use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, Context, Result};
 use crossbeam_channel::{Receiver, Sender};
-use dragonball::api::v1::{NetworkInterfaceConfig, VmmRequest, VmmResponse};
+use dragonball::api::v1::{VmmRequest, VmmResponse};
 use dragonball::device_manager::blk_dev_mgr::BlockDeviceConfigInfo;
 use dragonball::device_manager::fs_dev_mgr::FsMountConfigInfo;
 use dragonball::device_manager::vfio_dev_mgr::{HostDeviceConfig, VfioPciDeviceConfig};
 use dragonball::vcpu::VcpuResizeInfo;
-use serde_json::Value;
+use kvm_ioctls::Kvm;
+use serde_json::{json, Value};
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::affinity::{self, VcpuAffinity};
+use crate::cgroup::VmCgroup;
+use crate::clearcpuid::{self, ClearCpuid};
+use crate::console_log::ConsoleLog;
+use crate::cpu_topology;
+use crate::daemon::Readiness;
+use crate::digests::{self, MeasuredDigests};
+use crate::guest_mac::GuestMacTable;
+use crate::guest_net::{self, NetDevice};
+use crate::restart::{self, HotplugRecord};
+use crate::runtime_dir;
 use crate::utils;
+use crate::vhost_user::{VhostUserBlkConfig, VhostUserFsConfig};
 use crate::vmm_comm_trait::VMMComm;
 
 pub struct ApiServer {
     pub to_vmm: Option<Sender<VmmRequest>>,
     pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
     pub to_vmm_fd: EventFd,
+    pub vm_id: String,
+    pub guest_macs: GuestMacTable,
+    pub console_log: ConsoleLog,
+    pub hotplug_record: HotplugRecord,
+    /// the cgroup of the VM with --cgroup-path
+    pub vm_cgroup: Option<Arc<VmCgroup>>,
+    /// the vCPU pinning with --vcpu-affinity or --affinity-policy
+    pub vcpu_affinity: Option<Arc<VcpuAffinity>>,
+    /// the features hidden with --guest-clearcpuid-level or --guest-clearcpuid
+    pub clearcpuid: Option<Arc<ClearCpuid>>,
+    /// the /dev/kvm handle of dbs-cli, for the CPUID of the host model
+    pub kvm: Option<Arc<Kvm>>,
+    /// the digests of the boot artifacts measured at the last boot
+    pub measured_digests: MeasuredDigests,
 }
 
 impl VMMComm for ApiServer {
@@ -44,17 +71,33 @@ impl ApiServer {
         to_vmm: Option<Sender<VmmRequest>>,
         from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
         to_vmm_fd: EventFd,
+        vm_id: String,
+        guest_macs: GuestMacTable,
+        console_log: ConsoleLog,
+        hotplug_record: HotplugRecord,
     ) -> Self {
         ApiServer {
             to_vmm,
             from_vmm,
             to_vmm_fd,
+            vm_id,
+            guest_macs,
+            console_log,
+            hotplug_record,
+            vm_cgroup: None,
+            vcpu_affinity: None,
+            clearcpuid: None,
+            kvm: None,
+            measured_digests: MeasuredDigests::default(),
         }
     }
 
-    pub fn run_api_server(&mut self, api_sock_path: String) -> Result<()> {
+    pub fn run_api_server(&mut self, api_sock_path: String, readiness: Readiness) -> Result<()> {
         println!("dbs-cli: api server created in api_sock_path {:?}. Start waiting for connections from the client side.", &api_sock_path);
-        let unix_listener = UnixListener::bind(api_sock_path)?;
+        runtime_dir::remove_stale_socket(&api_sock_path)?;
+        let unix_listener = UnixListener::bind(&api_sock_path)?;
+        runtime_dir::register(&api_sock_path);
+        readiness.set_api_listening();
 
         // put the server logic in a loop to accept several connections
         loop {
@@ -74,12 +117,42 @@ impl ApiServer {
         // Parse the string of data into serde_json::Value.
         let v: Value = serde_json::from_str(&message)?;
 
+        self.handle_request(&v, &mut unix_stream)?;
+        // a restarted VM gets the hotplugged devices again.
+        self.hotplug_record.record(&v);
+        Ok(())
+    }
+
+    fn handle_request(&mut self, v: &Value, unix_stream: &mut UnixStream) -> Result<()> {
         match v["action"].as_str() {
             Some("resize_vcpu") => {
+                let vcpu_count = cpu_topology::check_vcpu_resize(
+                    v["vcpu_count"].as_u64(),
+                    self.get_vm_configuration()?.max_vcpu_count,
+                )?;
                 let resize_vcpu_cfg = VcpuResizeInfo {
-                    vcpu_count: v["vcpu_count"].as_u64().map(|count| count as u8),
+                    vcpu_count: Some(vcpu_count),
                 };
-                return self.resize_vcpu(resize_vcpu_cfg);
+                self.resize_vcpu(resize_vcpu_cfg)?;
+                // the threads of the added vCPUs join the vCPU cgroup, and
+                // are pinned as the others.
+                if let Some(vm_cgroup) = &self.vm_cgroup {
+                    vm_cgroup.place_vcpu_threads(vcpu_count as usize);
+                }
+                if let Some(vcpu_affinity) = &self.vcpu_affinity {
+                    vcpu_affinity.apply(vcpu_count as usize);
+                }
+                return Ok(());
+            }
+            Some("update_cgroup") => {
+                let vm_cgroup = self.vm_cgroup.as_ref().ok_or_else(|| {
+                    anyhow!("The VM has no cgroup, it was created without --cgroup-path")
+                })?;
+                vm_cgroup.update(
+                    v["cpu_max"].as_str(),
+                    v["cpuset"].as_str(),
+                    v["memory_max"].as_str(),
+                )?;
             }
             Some("insert_host_device") => {
                 // TODO: add customize support for sysfs_path, vendor_device_id, guest_dev_id and clique_id.
@@ -111,15 +184,21 @@ impl ApiServer {
                     Some(config_json) => config_json,
                     None => return Err(anyhow!("The config of virtio-net device is required")),
                 };
-                let configs: Vec<NetworkInterfaceConfig> = serde_json::from_str(config_json)
-                    .context("Parse NetworkInterfaceConfig from JSON")?;
-                for config in configs.iter() {
+                let net_devices =
+                    guest_net::parse_net_configs(config_json, &self.vm_id, &self.guest_macs)?;
+                for net_device in net_devices.iter() {
+                    let config = &net_device.config;
                     self.insert_virnet(config.clone()).with_context(|| {
                         format!(
                             "Insert a {} device to the Dragonball",
                             utils::net_device_name(config)
                         )
                     })?;
+                    self.guest_macs
+                        .insert(net_device.guest_mac, utils::net_device_name(config));
+                    if net_device.guest_net.is_some() {
+                        self.insert_net_config_disk(net_device)?;
+                    }
                 }
             }
             Some("insert_virblks") => {
@@ -129,11 +208,91 @@ impl ApiServer {
                 };
                 let configs: Vec<BlockDeviceConfigInfo> = serde_json::from_str(config_json)
                     .context("Parse virtio-blk device config from json")?;
+                let expected: Vec<(String, String)> = match &v["drive_sha256"] {
+                    Value::Null => vec![],
+                    expected => serde_json::from_value(expected.clone())
+                        .context("Parse the drive digests from json")?,
+                };
+                let drives: Vec<(String, String)> = configs
+                    .iter()
+                    .map(|config| {
+                        (
+                            config.drive_id.clone(),
+                            config.path_on_host.to_string_lossy().into_owned(),
+                        )
+                    })
+                    .collect();
+                digests::verify_hotplug(&drives, &expected, &self.measured_digests)?;
                 for config in configs.iter() {
                     self.insert_virblk(config.clone())
                         .context("Insert a virtio-blk device to the Dragonball")?;
                 }
             }
+            Some("insert_vhost_user_blks") => {
+                let config_json = match v["config"].as_str() {
+                    Some(config_json) => config_json,
+                    None => return Err(anyhow!("The config of vhost-user-blk device is required")),
+                };
+                let configs: Vec<VhostUserBlkConfig> = serde_json::from_str(config_json)
+                    .context("Parse vhost-user-blk device config from json")?;
+                for config in configs.into_iter() {
+                    self.insert_vhost_user_blk(config.into())
+                        .context("Insert a vhost-user-blk device to the Dragonball")?;
+                }
+            }
+            Some("insert_vhost_user_fs") => {
+                let config_json = match v["config"].as_str() {
+                    Some(config_json) => config_json,
+                    None => return Err(anyhow!("The config of vhost-user-fs device is required")),
+                };
+                let config: VhostUserFsConfig = serde_json::from_str(config_json)
+                    .context("Parse vhost-user-fs device config from json")?;
+                self.insert_fs(config.into())
+                    .context("Insert a vhost-user-fs device to the Dragonball")?;
+            }
+            Some("get_vm_info") => {
+                let vm_config = self.get_vm_configuration()?;
+                return reply(
+                    unix_stream,
+                    json!({
+                        "vm_id": self.vm_id,
+                        "vcpu_count": vm_config.vcpu_count,
+                        "max_vcpu_count": vm_config.max_vcpu_count,
+                        "mem_size_mib": vm_config.mem_size_mib,
+                        "cgroup": self.vm_cgroup.as_ref().map(|vm_cgroup| vm_cgroup.path()),
+                    }),
+                );
+            }
+            Some("get_vcpu_affinity") => {
+                return reply(unix_stream, affinity::query_vcpu_affinity());
+            }
+            Some("get_digests") => {
+                return reply(unix_stream, self.measured_digests.query());
+            }
+            Some("get_cpuid") => {
+                let kvm = self
+                    .kvm
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("/dev/kvm is not open"))?;
+                return reply(
+                    unix_stream,
+                    clearcpuid::query_cpuid(self.clearcpuid.as_deref(), kvm)?,
+                );
+            }
+            Some("shutdown") => {
+                restart::request_stop();
+                self.shutdown_microvm()?;
+            }
+            Some("get_console_log") => {
+                let size = v["size_kb"].as_u64().map_or(usize::MAX, |size_kb| {
+                    (size_kb as usize).saturating_mul(1024)
+                });
+                let console_log = self.console_log.tail(size);
+                return reply(
+                    unix_stream,
+                    json!({ "console_log": String::from_utf8_lossy(&console_log) }),
+                );
+            }
             Some("patch_fs") => {
                 let config_json = match v["config"].as_str() {
                     Some(config_json) => config_json,
@@ -149,7 +308,37 @@ impl ApiServer {
             }
         }
 
-        println!("{message}");
+        println!("{v}");
         Ok(())
     }
+
+    /// Attach the guest network config of a hotplugged network device as a
+    /// config disk of its own, named after its guest MAC address.
+    fn insert_net_config_disk(&self, net_device: &NetDevice) -> Result<()> {
+        let drive_id = format!(
+            "netcfg-{}",
+            net_device.guest_mac.to_string().replace(':', "")
+        );
+        let path = runtime_dir::path()
+            .ok_or_else(|| anyhow!("no runtime directory for the guest network config disk"))?
+            .join(format!("{drive_id}.img"));
+        guest_net::write_config_disk(std::slice::from_ref(net_device), &path)?;
+        runtime_dir::register(&path);
+        let config = BlockDeviceConfigInfo {
+            drive_id,
+            path_on_host: path,
+            is_root_device: false,
+            is_read_only: true,
+            ..BlockDeviceConfigInfo::default()
+        };
+        self.insert_virblk(config)
+            .context("Insert the guest network config disk to the Dragonball")
+    }
+}
+
+/// Send the response of a query back to the client.
+fn reply(unix_stream: &mut UnixStream, response: Value) -> Result<()> {
+    unix_stream
+        .write_all(response.to_string().as_bytes())
+        .context("Failed at writing the response onto the unix stream")
 }
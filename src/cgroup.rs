@@ -0,0 +1,287 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::parser::args::CgroupArgs;
+use crate::utils;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// The threaded child cgroup of the vCPU threads.
+const VCPU_CGROUP_NAME: &str = "vcpus";
+/// The controllers of the vCPU cgroup, which have to be threaded.
+const VCPU_CONTROLLERS: &[&str] = &["cpu", "cpuset"];
+
+/// A cgroup v2.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create the cgroup `name` in `parent`, relative to the cgroup v2 root,
+    /// with `controllers` enabled for it in all of its ancestors.
+    pub fn create(parent: &str, name: &str, controllers: &[&str]) -> Result<Self> {
+        let parent = cgroup_path(parent)?;
+        std::fs::create_dir_all(&parent)
+            .with_context(|| format!("Failed to create the cgroup {}", parent.display()))?;
+        let path = parent.join(name);
+        enable_in_ancestors(&path, controllers)?;
+        std::fs::create_dir(&path)
+            .with_context(|| format!("Failed to create the cgroup {}", path.display()))?;
+        Ok(Cgroup { path })
+    }
+
+    /// Create the threaded child cgroup `name`, for some of the threads of
+    /// the processes in this cgroup.
+    ///
+    /// The threaded controllers are enabled once the child is threaded, as
+    /// the cgroup has processes.
+    pub fn create_threaded(&self, name: &str, controllers: &[&str]) -> Result<Self> {
+        let path = self.path.join(name);
+        if !path.exists() {
+            std::fs::create_dir(&path)
+                .with_context(|| format!("Failed to create the cgroup {}", path.display()))?;
+        }
+        let cgroup = Cgroup { path };
+        cgroup.set("cgroup.type", "threaded")?;
+        enable_controllers(&self.path, controllers)?;
+        Ok(cgroup)
+    }
+
+    /// Write `value` to the interface file `key`, e.g. `memory.max`.
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        std::fs::write(self.path.join(key), value)
+            .with_context(|| format!("Failed to set {key} to {value} in {}", self.path.display()))
+    }
+
+    /// Move the process `pid` into the cgroup, 0 for the calling process.
+    pub fn add_process(&self, pid: u32) -> Result<()> {
+        self.set("cgroup.procs", &pid.to_string())
+    }
+
+    /// Move the thread `tid` into the threaded cgroup.
+    pub fn add_thread(&self, tid: i32) -> Result<()> {
+        self.set("cgroup.threads", &tid.to_string())
+    }
+
+    /// Remove the cgroup, once its processes have exited.
+    pub fn remove(&self) {
+        if let Err(err) = std::fs::remove_dir(&self.path) {
+            log::warn!(
+                "Failed to remove the cgroup {}: {:?}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// The path of the cgroup `path`, relative to the cgroup v2 root.
+fn cgroup_path(path: &str) -> Result<PathBuf> {
+    let root = Path::new(CGROUP_ROOT);
+    if !root.join("cgroup.controllers").exists() {
+        return Err(anyhow!("cgroup v2 is not mounted at {CGROUP_ROOT}"));
+    }
+    if path.split('/').any(|component| component == "..") {
+        return Err(anyhow!("invalid cgroup path {path}"));
+    }
+    Ok(root.join(path.trim_matches('/')))
+}
+
+/// Enable `controllers` for the cgroup `path` in the subtree of each of its
+/// ancestors.
+fn enable_in_ancestors(path: &Path, controllers: &[&str]) -> Result<()> {
+    let root = Path::new(CGROUP_ROOT);
+    let mut dir = root.to_path_buf();
+    enable_controllers(&dir, controllers)?;
+    for component in path.strip_prefix(root)?.components() {
+        dir.push(component);
+        if dir != path {
+            enable_controllers(&dir, controllers)?;
+        }
+    }
+    Ok(())
+}
+
+/// Enable `controllers` in the subtree of the cgroup `dir`, unless they are
+/// already, as a cgroup with processes cannot enable them.
+fn enable_controllers(dir: &Path, controllers: &[&str]) -> Result<()> {
+    let subtree_control = dir.join("cgroup.subtree_control");
+    let enabled = std::fs::read_to_string(&subtree_control)
+        .with_context(|| format!("Failed to read {}", subtree_control.display()))?;
+    for controller in controllers {
+        if enabled.split_whitespace().any(|c| c == *controller) {
+            continue;
+        }
+        std::fs::write(&subtree_control, format!("+{controller}")).with_context(|| {
+            format!(
+                "Failed to enable the {controller} controller in {}",
+                dir.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// The controllers of the cgroup interface files `keys`, e.g. `memory` for
+/// `memory.max`. The core interface files, e.g. `cgroup.max.depth`, need none.
+pub fn controllers<'a>(keys: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut controllers: Vec<&str> = keys
+        .into_iter()
+        .filter_map(|key| key.split_once('.').map(|(controller, _)| controller))
+        .filter(|controller| *controller != "cgroup")
+        .collect();
+    controllers.sort_unstable();
+    controllers.dedup();
+    controllers
+}
+
+/// The cgroup v2 of the calling process.
+fn current_cgroup() -> Result<PathBuf> {
+    let content =
+        std::fs::read_to_string("/proc/self/cgroup").context("Failed to read /proc/self/cgroup")?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+        .ok_or_else(|| anyhow!("dbs-cli is not in a cgroup v2"))
+}
+
+/// The cgroup of the VM given by `--cgroup-path`, which dbs-cli runs in, and
+/// the threaded child cgroup of its vCPU threads with `--cgroup-vcpu`.
+pub struct VmCgroup {
+    path: String,
+    cgroup: Cgroup,
+    vcpus: Option<Cgroup>,
+    /// the cgroup dbs-cli was in, to leave the VM cgroup before removing it
+    original: PathBuf,
+    created: bool,
+}
+
+impl VmCgroup {
+    /// Move dbs-cli into the cgroup of `--cgroup-path`, created if it does
+    /// not exist, with the limits of the arguments.
+    pub fn setup(args: &CgroupArgs) -> Result<Option<Self>> {
+        let path = match &args.cgroup_path {
+            Some(path) => path,
+            None => {
+                if args.cgroup_cpu_max.is_some()
+                    || args.cgroup_cpuset.is_some()
+                    || args.cgroup_memory_max.is_some()
+                    || args.cgroup_vcpu
+                {
+                    return Err(anyhow!("the cgroup limits require --cgroup-path"));
+                }
+                return Ok(None);
+            }
+        };
+        let mut controllers = vec![];
+        if args.cgroup_cpu_max.is_some() || args.cgroup_vcpu {
+            controllers.push("cpu");
+        }
+        if args.cgroup_cpuset.is_some() || args.cgroup_vcpu {
+            controllers.push("cpuset");
+        }
+        if args.cgroup_memory_max.is_some() {
+            controllers.push("memory");
+        }
+
+        let original = current_cgroup()?;
+        let full_path = cgroup_path(path)?;
+        let created = !full_path.exists();
+        let cgroup = if created {
+            let (parent, name) = path
+                .trim_matches('/')
+                .rsplit_once('/')
+                .unwrap_or(("", path.trim_matches('/')));
+            Cgroup::create(parent, name, &controllers)?
+        } else {
+            enable_in_ancestors(&full_path, &controllers)?;
+            Cgroup { path: full_path }
+        };
+
+        let mut vm_cgroup = VmCgroup {
+            path: path.clone(),
+            cgroup,
+            vcpus: None,
+            original,
+            created,
+        };
+        if let Err(err) = vm_cgroup.apply(args) {
+            vm_cgroup.cleanup();
+            return Err(err);
+        }
+        Ok(Some(vm_cgroup))
+    }
+
+    fn apply(&mut self, args: &CgroupArgs) -> Result<()> {
+        self.update(
+            args.cgroup_cpu_max.as_deref(),
+            args.cgroup_cpuset.as_deref(),
+            args.cgroup_memory_max.as_deref(),
+        )?;
+        self.cgroup.add_process(0)?;
+        if args.cgroup_vcpu {
+            self.vcpus = Some(
+                self.cgroup
+                    .create_threaded(VCPU_CGROUP_NAME, VCPU_CONTROLLERS)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// The path of the cgroup, relative to the cgroup v2 root.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Change the limits of the VM, e.g. after its vCPUs are resized.
+    pub fn update(
+        &self,
+        cpu_max: Option<&str>,
+        cpuset: Option<&str>,
+        memory_max: Option<&str>,
+    ) -> Result<()> {
+        if let Some(cpu_max) = cpu_max {
+            self.cgroup.set("cpu.max", cpu_max)?;
+        }
+        if let Some(cpuset) = cpuset {
+            self.cgroup.set("cpuset.cpus", cpuset)?;
+        }
+        if let Some(memory_max) = memory_max {
+            self.cgroup.set("memory.max", memory_max)?;
+        }
+        Ok(())
+    }
+
+    /// Move the vCPU threads into the vCPU cgroup, once `vcpu_count` vCPUs
+    /// are running.
+    pub fn place_vcpu_threads(&self, vcpu_count: usize) {
+        let vcpus = match &self.vcpus {
+            Some(vcpus) => vcpus,
+            None => return,
+        };
+        for (_, tid) in utils::wait_vcpu_threads(vcpu_count) {
+            if let Err(err) = vcpus.add_thread(tid) {
+                log::warn!("Failed to move vCPU thread {}: {:?}", tid, err);
+            }
+        }
+    }
+
+    /// Move dbs-cli back into its original cgroup, and remove the cgroups it
+    /// created.
+    pub fn cleanup(&self) {
+        if let Err(err) = std::fs::write(self.original.join("cgroup.procs"), "0") {
+            log::warn!("Failed to leave the cgroup {}: {:?}", self.path, err);
+            return;
+        }
+        if let Some(vcpus) = &self.vcpus {
+            vcpus.remove();
+        }
+        if self.created {
+            self.cgroup.remove();
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::clearcpuid;
+use crate::parser::args::CheckHostArgs;
+
+const KVM_DEVICE: &str = "/dev/kvm";
+const VHOST_NET_DEVICE: &str = "/dev/vhost-net";
+const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
+const TUN_DEVICE: &str = "/dev/net/tun";
+const VFIO_DEVICE: &str = "/dev/vfio/vfio";
+const HUGEPAGES_SYSFS_DIR: &str = "/sys/kernel/mm/hugepages";
+const IOMMU_GROUPS_DIR: &str = "/sys/kernel/iommu_groups";
+const CGROUP_CONTROLLERS: &str = "/sys/fs/cgroup/cgroup.controllers";
+const SECCOMP_ACTIONS: &str = "/proc/sys/kernel/seccomp/actions_avail";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+/// The result of a check of the host. The missing features dbs-cli cannot
+/// run without fail, the others only warn.
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+fn check(name: &'static str, status: Status, detail: impl Into<String>) -> Check {
+    Check {
+        name,
+        status,
+        detail: detail.into(),
+    }
+}
+
+/// Check whether the host can run Dragonball VMs, and print the results as a
+/// table or JSON. Returns 1 if a check failed.
+pub fn run_check_host(args: CheckHostArgs) -> Result<i32> {
+    let mut checks = check_kvm();
+    checks.push(check_device(
+        "vhost-net",
+        VHOST_NET_DEVICE,
+        "needed by vhost-net devices, load the vhost_net module",
+    ));
+    checks.push(check_device(
+        "vhost-vsock",
+        VHOST_VSOCK_DEVICE,
+        "needed by vsock devices, load the vhost_vsock module",
+    ));
+    checks.push(check_device(
+        "tun",
+        TUN_DEVICE,
+        "needed by virtio-net and vhost-net devices, load the tun module",
+    ));
+    checks.push(check_hugepages());
+    checks.push(check_iommu());
+    checks.push(check_device(
+        "vfio",
+        VFIO_DEVICE,
+        "needed by --host-device, load the vfio-pci module",
+    ));
+    checks.push(check_seccomp());
+    checks.push(check_cgroup());
+
+    if args.json {
+        let checks: Vec<Value> = checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "name": check.name,
+                    "status": check.status.name(),
+                    "detail": check.detail,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({ "checks": checks }))?
+        );
+    } else {
+        println!("{:<6} {:<14} DETAIL", "STATUS", "CHECK");
+        for check in checks.iter() {
+            println!(
+                "{:<6} {:<14} {}",
+                check.status.name(),
+                check.name,
+                check.detail
+            );
+        }
+    }
+
+    let failed = checks.iter().any(|check| check.status == Status::Fail);
+    Ok(failed as i32)
+}
+
+/// The access to /dev/kvm, its API version and capabilities.
+fn check_kvm() -> Vec<Check> {
+    if let Err(err) = OpenOptions::new().read(true).write(true).open(KVM_DEVICE) {
+        let detail = match err.kind() {
+            ErrorKind::NotFound => format!(
+                "{KVM_DEVICE} does not exist, enable virtualization in the firmware and load the kvm_intel or kvm_amd module"
+            ),
+            ErrorKind::PermissionDenied => format!(
+                "no access to {KVM_DEVICE}, run dbs-cli as root or in the group owning {KVM_DEVICE}"
+            ),
+            _ => format!("failed to open {KVM_DEVICE}: {err}"),
+        };
+        return vec![check("kvm", Status::Fail, detail)];
+    }
+    let kvm = match kvm_ioctls::Kvm::new() {
+        Ok(kvm) => kvm,
+        Err(err) => return vec![check("kvm", Status::Fail, format!("{err}"))],
+    };
+
+    let mut checks = vec![];
+    let version = kvm.get_api_version();
+    if version == kvm_bindings::KVM_API_VERSION as i32 {
+        checks.push(check("kvm", Status::Pass, format!("API version {version}")));
+    } else {
+        checks.push(check(
+            "kvm",
+            Status::Fail,
+            format!(
+                "API version {version}, expected {}",
+                kvm_bindings::KVM_API_VERSION
+            ),
+        ));
+    }
+    checks.push(check(
+        "kvm-vcpus",
+        Status::Pass,
+        format!(
+            "max {} vCPUs per VM, {} recommended",
+            kvm.get_max_vcpus(),
+            kvm.get_nr_vcpus()
+        ),
+    ));
+    checks.push(match clearcpuid::supported_levels(&kvm) {
+        Ok((entries, levels)) if levels.is_empty() => check(
+            "cpuid",
+            Status::Warn,
+            format!("{entries} entries, no --guest-clearcpuid-level is supported"),
+        ),
+        Ok((entries, levels)) => check(
+            "cpuid",
+            Status::Pass,
+            format!(
+                "{entries} entries, --guest-clearcpuid-level {}",
+                levels.join(", ")
+            ),
+        ),
+        Err(err) => check("cpuid", Status::Warn, format!("{err:#}")),
+    });
+    checks
+}
+
+/// The access to a device node dbs-cli runs without, but some devices need.
+fn check_device(name: &'static str, path: &str, hint: &str) -> Check {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => check(name, Status::Pass, format!("{path} is available")),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            check(name, Status::Warn, format!("{path} does not exist, {hint}"))
+        }
+        Err(err) => check(
+            name,
+            Status::Warn,
+            format!("failed to open {path}: {err}, {hint}"),
+        ),
+    }
+}
+
+/// The free pages of the hugepage pools, for `--mem-type hugetlbfs`.
+fn check_hugepages() -> Check {
+    let dir = match std::fs::read_dir(HUGEPAGES_SYSFS_DIR) {
+        Ok(dir) => dir,
+        Err(_) => return check("hugepages", Status::Warn, "hugepages are not supported"),
+    };
+    let mut pools: Vec<(u64, u64, u64)> = dir
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let size_kb = name
+                .strip_prefix("hugepages-")?
+                .strip_suffix("kB")?
+                .parse()
+                .ok()?;
+            let read = |file: &str| -> u64 {
+                std::fs::read_to_string(entry.path().join(file))
+                    .ok()
+                    .and_then(|count| count.trim().parse().ok())
+                    .unwrap_or(0)
+            };
+            Some((size_kb, read("nr_hugepages"), read("free_hugepages")))
+        })
+        .collect();
+    pools.sort_unstable();
+    let detail = pools
+        .iter()
+        .map(|(size_kb, total, free)| format!("{}: {free}/{total} free", format_size(*size_kb)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if pools.iter().any(|(_, _, free)| *free > 0) {
+        check("hugepages", Status::Pass, detail)
+    } else {
+        check(
+            "hugepages",
+            Status::Warn,
+            format!("{detail}; no free hugepages for --mem-type hugetlbfs"),
+        )
+    }
+}
+
+fn format_size(size_kb: u64) -> String {
+    if size_kb >= 1 << 20 {
+        format!("{}G", size_kb >> 20)
+    } else {
+        format!("{}M", size_kb >> 10)
+    }
+}
+
+/// The IOMMU groups of the host, which VFIO passes devices through by.
+fn check_iommu() -> Check {
+    let groups = std::fs::read_dir(IOMMU_GROUPS_DIR)
+        .map(|dir| dir.count())
+        .unwrap_or(0);
+    if groups > 0 {
+        check("iommu", Status::Pass, format!("{groups} IOMMU groups"))
+    } else {
+        check(
+            "iommu",
+            Status::Warn,
+            "no IOMMU groups, needed by --host-device, enable the IOMMU in the firmware and with intel_iommu=on or amd_iommu=on",
+        )
+    }
+}
+
+/// The seccomp filters and the actions `--seccomp-level` and
+/// `--seccomp-audit` use.
+fn check_seccomp() -> Check {
+    // SAFETY: PR_GET_SECCOMP takes no arguments.
+    if unsafe { libc::prctl(libc::PR_GET_SECCOMP) } < 0 {
+        return check(
+            "seccomp",
+            Status::Warn,
+            "the kernel has no seccomp, run with --seccomp-level none",
+        );
+    }
+    let actions = std::fs::read_to_string(SECCOMP_ACTIONS).unwrap_or_default();
+    let actions: Vec<&str> = actions.split_whitespace().collect();
+    if !actions.contains(&"trap") {
+        check(
+            "seccomp",
+            Status::Warn,
+            "the kernel has no seccomp filters, run with --seccomp-level none",
+        )
+    } else if !actions.contains(&"log") {
+        check(
+            "seccomp",
+            Status::Warn,
+            "seccomp filters are supported, --seccomp-audit is not",
+        )
+    } else {
+        check("seccomp", Status::Pass, "seccomp filters are supported")
+    }
+}
+
+/// The cgroup v2 hierarchy, for `--cgroup-path` and the jail.
+fn check_cgroup() -> Check {
+    match std::fs::read_to_string(CGROUP_CONTROLLERS) {
+        Ok(controllers) => check(
+            "cgroup-v2",
+            Status::Pass,
+            format!("controllers: {}", controllers.trim()),
+        ),
+        _ => check(
+            "cgroup-v2",
+            Status::Warn,
+            "cgroup v2 is not mounted at /sys/fs/cgroup, needed by --cgroup-path and dbs-cli jail --cgroup",
+        ),
+    }
+}
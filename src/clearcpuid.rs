@@ -0,0 +1,353 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The CPU features hidden from the guest kernel with `clearcpuid=`.
+//!
+//! This is no CPU model: the VM config of Dragonball takes no CPUID, so the
+//! vCPUs get the CPUID KVM supports on the host, and only the guest kernel
+//! is told not to use some features. It neither uses them nor lists them in
+//! /proc/cpuinfo, but the CPUID instruction still reports them to guest
+//! applications. Only the features listed in FEATURES are hidden, so a level
+//! such as x86-64-v2 or x86-64-v3 leaves the other features of the host,
+//! e.g. AVX512-VNNI, visible to the guest kernel as well.
+
+use anyhow::{anyhow, Result};
+use kvm_ioctls::Kvm;
+use serde_json::{json, Value};
+
+use crate::parser::args::{ClearCpuidLevel, CpuArgs};
+use crate::utils;
+
+/// The CPUID leaves of the features, which are shown by the queries.
+const FEATURE_LEAVES: &[(u32, u32)] = &[(0x1, 0), (0x7, 0), (0x8000_0001, 0)];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A CPU feature in CPUID, named as in /proc/cpuinfo of Linux, which is the
+/// name `clearcpuid=` takes as well.
+struct Feature {
+    name: &'static str,
+    leaf: u32,
+    subleaf: u32,
+    reg: Reg,
+    bit: u32,
+}
+
+const fn feature(name: &'static str, leaf: u32, reg: Reg, bit: u32) -> Feature {
+    Feature {
+        name,
+        leaf,
+        subleaf: 0,
+        reg,
+        bit,
+    }
+}
+
+/// The features of the x86-64 levels, and the ones hidden most often.
+const FEATURES: &[Feature] = &[
+    feature("pni", 0x1, Reg::Ecx, 0),
+    feature("pclmulqdq", 0x1, Reg::Ecx, 1),
+    feature("ssse3", 0x1, Reg::Ecx, 9),
+    feature("fma", 0x1, Reg::Ecx, 12),
+    feature("cx16", 0x1, Reg::Ecx, 13),
+    feature("sse4_1", 0x1, Reg::Ecx, 19),
+    feature("sse4_2", 0x1, Reg::Ecx, 20),
+    feature("movbe", 0x1, Reg::Ecx, 22),
+    feature("popcnt", 0x1, Reg::Ecx, 23),
+    feature("aes", 0x1, Reg::Ecx, 25),
+    feature("xsave", 0x1, Reg::Ecx, 26),
+    feature("avx", 0x1, Reg::Ecx, 28),
+    feature("f16c", 0x1, Reg::Ecx, 29),
+    feature("rdrand", 0x1, Reg::Ecx, 30),
+    feature("bmi1", 0x7, Reg::Ebx, 3),
+    feature("hle", 0x7, Reg::Ebx, 4),
+    feature("avx2", 0x7, Reg::Ebx, 5),
+    feature("bmi2", 0x7, Reg::Ebx, 8),
+    feature("rtm", 0x7, Reg::Ebx, 11),
+    feature("avx512f", 0x7, Reg::Ebx, 16),
+    feature("avx512dq", 0x7, Reg::Ebx, 17),
+    feature("rdseed", 0x7, Reg::Ebx, 18),
+    feature("adx", 0x7, Reg::Ebx, 19),
+    feature("avx512cd", 0x7, Reg::Ebx, 28),
+    feature("sha_ni", 0x7, Reg::Ebx, 29),
+    feature("avx512bw", 0x7, Reg::Ebx, 30),
+    feature("avx512vl", 0x7, Reg::Ebx, 31),
+    feature("gfni", 0x7, Reg::Ecx, 8),
+    feature("vaes", 0x7, Reg::Ecx, 9),
+    feature("vpclmulqdq", 0x7, Reg::Ecx, 10),
+    feature("lahf_lm", 0x8000_0001, Reg::Ecx, 0),
+    feature("abm", 0x8000_0001, Reg::Ecx, 5),
+    feature("pdpe1gb", 0x8000_0001, Reg::Edx, 26),
+];
+
+const X86_64_V2: &[&str] = &[
+    "cx16", "lahf_lm", "popcnt", "pni", "sse4_1", "sse4_2", "ssse3",
+];
+const X86_64_V3: &[&str] = &[
+    "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+];
+const X86_64_V4: &[&str] = &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"];
+
+impl ClearCpuidLevel {
+    fn name(self) -> &'static str {
+        match self {
+            ClearCpuidLevel::Host => "host",
+            ClearCpuidLevel::X86_64V2 => "x86-64-v2",
+            ClearCpuidLevel::X86_64V3 => "x86-64-v3",
+            ClearCpuidLevel::X86_64V4 => "x86-64-v4",
+        }
+    }
+
+    /// The features the guest kernel keeps at the level, None for the host.
+    fn features(self) -> Option<Vec<&'static str>> {
+        let levels: &[&[&str]] = match self {
+            ClearCpuidLevel::Host => return None,
+            ClearCpuidLevel::X86_64V2 => &[X86_64_V2],
+            ClearCpuidLevel::X86_64V3 => &[X86_64_V2, X86_64_V3],
+            ClearCpuidLevel::X86_64V4 => &[X86_64_V2, X86_64_V3, X86_64_V4],
+        };
+        Some(levels.concat())
+    }
+}
+
+/// The features of a name in `--guest-clearcpuid`, which also takes the names of
+/// the instruction sets besides the names of Linux.
+fn lookup_features(name: &str) -> Result<Vec<&'static Feature>> {
+    let names: &[&str] = match name {
+        "tsx" => &["hle", "rtm"],
+        "sse3" => &["pni"],
+        "lzcnt" => &["abm"],
+        "sha" => &["sha_ni"],
+        name => &[name][..],
+    };
+    names
+        .iter()
+        .map(|name| {
+            FEATURES
+                .iter()
+                .find(|feature| feature.name == *name)
+                .ok_or_else(|| anyhow!("unknown CPU feature {name:?}"))
+        })
+        .collect()
+}
+
+/// A CPUID leaf KVM supports for the guests.
+#[derive(Clone)]
+struct CpuidEntry {
+    leaf: u32,
+    subleaf: u32,
+    regs: [u32; 3],
+}
+
+impl CpuidEntry {
+    fn has(&self, feature: &Feature) -> bool {
+        self.leaf == feature.leaf
+            && self.subleaf == feature.subleaf
+            && self.regs[feature.reg as usize] & (1 << feature.bit) != 0
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn supported_cpuid(kvm: &Kvm) -> Result<Vec<(CpuidEntry, u32)>> {
+    use anyhow::Context;
+
+    let cpuid = kvm
+        .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+        .context("Failed to get the CPUID KVM supports")?;
+    Ok(cpuid
+        .as_slice()
+        .iter()
+        .map(|entry| {
+            (
+                CpuidEntry {
+                    leaf: entry.function,
+                    subleaf: entry.index,
+                    regs: [entry.ebx, entry.ecx, entry.edx],
+                },
+                entry.eax,
+            )
+        })
+        .collect())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn supported_cpuid(_kvm: &Kvm) -> Result<Vec<(CpuidEntry, u32)>> {
+    Err(anyhow!("clearcpuid= is supported on x86_64 only"))
+}
+
+/// The leaves of FEATURE_LEAVES KVM supports.
+fn feature_cpuid(kvm: &Kvm) -> Result<Vec<(CpuidEntry, u32)>> {
+    Ok(supported_cpuid(kvm)?
+        .into_iter()
+        .filter(|(entry, _)| FEATURE_LEAVES.contains(&(entry.leaf, entry.subleaf)))
+        .collect())
+}
+
+/// The features hidden from the guest kernel, from
+/// `--guest-clearcpuid-level` and `--guest-clearcpuid`, checked against the
+/// CPUID KVM supports on the host.
+pub struct ClearCpuid {
+    level: ClearCpuidLevel,
+    /// the CPUID leaves of FEATURE_LEAVES KVM supports, with their eax
+    cpuid: Vec<(CpuidEntry, u32)>,
+    /// the features of FEATURES the host supports but the guest kernel does
+    /// not use
+    hidden: Vec<&'static Feature>,
+}
+
+impl ClearCpuid {
+    pub fn new(args: &CpuArgs, kvm: &Kvm) -> Result<Option<Self>> {
+        if args.guest_clearcpuid_level == ClearCpuidLevel::Host && args.guest_clearcpuid.is_none() {
+            return Ok(None);
+        }
+        let cpuid = feature_cpuid(kvm)?;
+        let host_has = |feature: &Feature| cpuid.iter().any(|(entry, _)| entry.has(feature));
+
+        let mut guest: Vec<&'static Feature> = match args.guest_clearcpuid_level.features() {
+            Some(names) => {
+                let features = names
+                    .iter()
+                    .map(|name| lookup_features(name))
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                let missing: Vec<&str> = features
+                    .iter()
+                    .filter(|feature| !host_has(feature))
+                    .map(|feature| feature.name)
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(anyhow!(
+                        "the host does not support {} of --guest-clearcpuid-level {}",
+                        missing.join(","),
+                        args.guest_clearcpuid_level.name()
+                    ));
+                }
+                features
+            }
+            None => FEATURES
+                .iter()
+                .filter(|feature| host_has(feature))
+                .collect(),
+        };
+        for (kept, name) in args
+            .guest_clearcpuid
+            .iter()
+            .flat_map(|features| &features.0)
+        {
+            for feature in lookup_features(name)? {
+                if !*kept {
+                    guest.retain(|f| f.name != feature.name);
+                } else if !host_has(feature) {
+                    return Err(anyhow!("the host does not support the CPU feature {name}"));
+                } else if !guest.iter().any(|f| f.name == feature.name) {
+                    guest.push(feature);
+                }
+            }
+        }
+
+        let hidden = FEATURES
+            .iter()
+            .filter(|feature| host_has(feature))
+            .filter(|feature| !guest.iter().any(|f| f.name == feature.name))
+            .collect();
+        Ok(Some(ClearCpuid {
+            level: args.guest_clearcpuid_level,
+            cpuid,
+            hidden,
+        }))
+    }
+
+    /// Hide the features from the guest kernel with `clearcpuid=`.
+    ///
+    /// The VM config of Dragonball takes no CPUID, so the guest still reads
+    /// the features with the CPUID instruction, but the kernel neither uses
+    /// them nor lists them in /proc/cpuinfo.
+    pub fn boot_args(&self, boot_args: &str) -> String {
+        if self.hidden.is_empty() {
+            return boot_args.to_string();
+        }
+        if utils::has_kernel_param(boot_args, "clearcpuid") {
+            log::warn!(
+                "the clearcpuid= boot arg is replaced by the features of --guest-clearcpuid"
+            );
+        }
+        let names: Vec<&str> = self.hidden.iter().map(|feature| feature.name).collect();
+        utils::set_kernel_param(boot_args, "clearcpuid", &names.join(","))
+    }
+
+    /// The `get_cpuid` query: the features the guest kernel uses, the ones
+    /// hidden from it with `clearcpuid=`, and the CPUID the host supports,
+    /// which is what the vCPUs report.
+    pub fn query(&self) -> Value {
+        let host_leaves: Vec<Value> = self
+            .cpuid
+            .iter()
+            .map(|(entry, eax)| {
+                json!({
+                    "leaf": format!("{:#x}", entry.leaf),
+                    "subleaf": entry.subleaf,
+                    "eax": format!("{eax:#010x}"),
+                    "ebx": format!("{:#010x}", entry.regs[Reg::Ebx as usize]),
+                    "ecx": format!("{:#010x}", entry.regs[Reg::Ecx as usize]),
+                    "edx": format!("{:#010x}", entry.regs[Reg::Edx as usize]),
+                })
+            })
+            .collect();
+        let clearcpuid: Vec<&str> = self.hidden.iter().map(|feature| feature.name).collect();
+        let kernel_features: Vec<&str> = FEATURES
+            .iter()
+            .filter(|feature| self.cpuid.iter().any(|(entry, _)| entry.has(feature)))
+            .filter(|feature| !clearcpuid.contains(&feature.name))
+            .map(|feature| feature.name)
+            .collect();
+        json!({
+            "clearcpuid_level": self.level.name(),
+            "kernel_features": kernel_features,
+            "clearcpuid": clearcpuid,
+            "host_leaves": host_leaves,
+        })
+    }
+}
+
+/// The number of CPUID entries KVM supports on the host, and the x86-64
+/// levels of which it supports all the features.
+pub fn supported_levels(kvm: &Kvm) -> Result<(usize, Vec<&'static str>)> {
+    let entries = supported_cpuid(kvm)?;
+    let host_has = |name: &&str| {
+        lookup_features(name)
+            .unwrap_or_default()
+            .iter()
+            .all(|feature| entries.iter().any(|(entry, _)| entry.has(feature)))
+    };
+    let levels = [
+        ClearCpuidLevel::X86_64V2,
+        ClearCpuidLevel::X86_64V3,
+        ClearCpuidLevel::X86_64V4,
+    ]
+    .into_iter()
+    .filter(|level| level.features().unwrap_or_default().iter().all(host_has))
+    .map(ClearCpuidLevel::name)
+    .collect();
+    Ok((entries.len(), levels))
+}
+
+/// The CPUID of a VM, `clearcpuid` None if nothing is hidden from the guest
+/// kernel.
+pub fn query_cpuid(clearcpuid: Option<&ClearCpuid>, kvm: &Kvm) -> Result<Value> {
+    match clearcpuid {
+        Some(clearcpuid) => Ok(clearcpuid.query()),
+        None => {
+            let clearcpuid = ClearCpuid {
+                level: ClearCpuidLevel::Host,
+                cpuid: feature_cpuid(kvm)?,
+                hidden: vec![],
+            };
+            Ok(clearcpuid.query())
+        }
+    }
+}
@@ -5,11 +5,20 @@
 //
 
 use std::{
-    path::{Path, PathBuf},
+    path::PathBuf,
     sync::{Arc, Mutex, RwLock},
 };
 
-use crate::{parser::args::CreateArgs, vmm_comm_trait::VMMComm};
+use crate::{
+    console_server,
+    digests::{self, MeasuredDigests},
+    guest_mac::GuestMacTable,
+    guest_net, guest_panic,
+    parser::args::CreateArgs,
+    runtime_dir, stdio_console, utils,
+    vhost_user::{VhostUserBlkConfig, VhostUserFsConfig},
+    vmm_comm_trait::VMMComm,
+};
 use anyhow::{anyhow, Result};
 use crossbeam_channel::{Receiver, Sender};
 use seccompiler::BpfProgram;
@@ -17,8 +26,8 @@ use vmm_sys_util::eventfd::EventFd;
 
 use dragonball::{
     api::v1::{
-        BlockDeviceConfigInfo, BootSourceConfig, InstanceInfo, NetworkInterfaceConfig, VmmRequest,
-        VmmResponse, VsockDeviceConfigInfo,
+        BlockDeviceConfigInfo, BootSourceConfig, InstanceInfo, VmmRequest, VmmResponse,
+        VsockDeviceConfigInfo,
     },
     device_manager::{
         fs_dev_mgr::FsDeviceConfigInfo,
@@ -35,7 +44,13 @@ pub struct CliInstance {
     pub to_vmm: Option<Sender<VmmRequest>>,
     pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
     pub to_vmm_fd: EventFd,
-    pub seccomp: BpfProgram,
+    /// seccomp filters of the VMM and vCPU threads
+    pub vmm_seccomp: BpfProgram,
+    pub vcpu_seccomp: BpfProgram,
+    /// guest MAC addresses of the network devices, shared with the api server
+    pub guest_macs: GuestMacTable,
+    /// the digests of the boot artifacts, shared with the api server
+    pub measured_digests: MeasuredDigests,
 }
 
 impl VMMComm for CliInstance {
@@ -66,21 +81,43 @@ impl CliInstance {
             to_vmm: None,
             from_vmm: None,
             to_vmm_fd,
-            seccomp: vec![],
+            vmm_seccomp: vec![],
+            vcpu_seccomp: vec![],
+            guest_macs: GuestMacTable::default(),
+            measured_digests: MeasuredDigests::default(),
         }
     }
 
+    /// Share the VMM instance info and channels, for the configuration of a
+    /// restarted VM.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(CliInstance {
+            vmm_shared_info: self.vmm_shared_info.clone(),
+            to_vmm: self.to_vmm.clone(),
+            from_vmm: self.from_vmm.clone(),
+            to_vmm_fd: self.to_vmm_fd.try_clone()?,
+            vmm_seccomp: self.vmm_seccomp.clone(),
+            vcpu_seccomp: self.vcpu_seccomp.clone(),
+            guest_macs: self.guest_macs.clone(),
+            measured_digests: self.measured_digests.clone(),
+        })
+    }
+
     pub fn run_vmm_server(&self, args: CreateArgs) -> Result<()> {
         if args.kernel_path.is_none() || args.rootfs_args.rootfs.is_none() {
             return Err(anyhow!(
                 "kernel path or rootfs path cannot be None when creating the VM"
             ));
         }
-        let mut serial_path: Option<String> = None;
-
-        if args.serial_path != "stdio" {
-            serial_path = Some(args.serial_path);
-        }
+        // the artifacts are measured again at each boot of a restarted VM.
+        digests::verify(&args, &self.measured_digests)?;
+        // the console is served by dbs-cli, either on stdio or on the serial
+        // path, which connects to the Dragonball serial console on a socket.
+        let serial_path = if args.serial_path == "stdio" {
+            stdio_console::vmm_sock_path()?
+        } else {
+            console_server::vmm_sock_path(&args.serial_path)
+        };
 
         // configuration
         let vm_config = VmConfigInfo {
@@ -97,27 +134,60 @@ impl CliInstance {
             mem_type: args.mem.mem_type.clone(),
             mem_file_path: args.mem.mem_file_path.clone(),
             mem_size_mib: args.mem.mem_size,
-            // as in crate `dragonball` serial_path will be assigned with a default value,
-            // we need a special token to enable the stdio console.
-            serial_path: serial_path.clone(),
+            serial_path: Some(serial_path.clone()),
             pci_hotplug_enabled: args.host_device.pci_hotplug_enabled,
         };
 
-        if let Some(com1_sock_path) = serial_path {
-            // check the existence of the serial path (rm it if exist)
-            // unwrap is safe  because we have check it is Some above.
-            let serial_file = Path::new(com1_sock_path.as_str());
-            if serial_file.exists() {
-                std::fs::remove_file(serial_file)?;
+        // remove the serial socket of a previous run, the sockets of the
+        // console server stay across VM restarts.
+        runtime_dir::remove_stale_socket(&serial_path)?;
+        runtime_dir::register(&serial_path);
+
+        // network devices are parsed ahead, as their guest network config goes
+        // to the boot args.
+        let net_devices = if args.virnets.is_empty() {
+            vec![]
+        } else {
+            let vm_id = self.vmm_shared_info.read().unwrap().id.clone();
+            guest_net::parse_net_configs(&args.virnets, &vm_id, &self.guest_macs).unwrap_or_else(
+                |err| {
+                    panic!(
+                        "Failed to parse NetworkInterfaceConfig from JSON: {:?}",
+                        err
+                    )
+                },
+            )
+        };
+
+        // the kernel configures the first network device by itself.
+        let mut boot_args = args.boot_args.clone();
+        if let Some(guest_net) = net_devices.first().and_then(|dev| dev.guest_net.as_ref()) {
+            if utils::has_kernel_param(&boot_args, "ip") {
+                log::warn!("ip= is already in the boot args, the guest network config of the first network device is not applied by the kernel");
+            } else {
+                boot_args = utils::append_kernel_param(&boot_args, &guest_net.kernel_ip_param());
+            }
+        }
+
+        // the guest dmesg goes to the second serial port, while the first one
+        // stays the console.
+        if args.dmesg_file.is_some() {
+            if !utils::has_kernel_param_value(&boot_args, "console=ttyS1") {
+                boot_args = utils::prepend_kernel_param(&boot_args, "console=ttyS1");
+            }
+            if !utils::has_kernel_param(&boot_args, "earlyprintk") {
+                boot_args = utils::append_kernel_param(&boot_args, "earlyprintk=ttyS1");
             }
         }
 
+        let boot_args = guest_panic::panic_boot_args(&boot_args, args.guest_panic.on_panic);
+
         // boot source
         let boot_source_config = BootSourceConfig {
             // unwrap is safe because we have checked kernel_path in the beginning of run_vmm_server
             kernel_path: args.kernel_path.unwrap(),
             initrd_path: args.initrd_path.clone(),
-            boot_args: Some(args.boot_args.clone()),
+            boot_args: Some(boot_args),
         };
 
         // rootfs
@@ -144,6 +214,8 @@ impl CliInstance {
             .expect("failed to set block device");
 
         if !args.vsock.is_empty() {
+            runtime_dir::remove_stale_socket(&args.vsock)?;
+            runtime_dir::register(&args.vsock);
             // VSOCK config
             let mut vsock_config_info = VsockDeviceConfigInfo::default();
             vsock_config_info = VsockDeviceConfigInfo {
@@ -179,15 +251,13 @@ impl CliInstance {
                 .expect("Failed to insert a host device");
         }
         // Virtio devices
-        if !args.virnets.is_empty() {
-            let configs: Vec<NetworkInterfaceConfig> = serde_json::from_str(&args.virnets)
-                .unwrap_or_else(|err| {
-                    panic!("Failed to parse NetworkInterfaceConfig from JSON: {}", err)
-                });
-            for config in configs.into_iter() {
-                self.insert_virnet(config)
-                    .expect("Failed to insert a virtio device");
-            }
+        for net_device in net_devices.iter() {
+            self.insert_virnet(net_device.config.clone())
+                .expect("Failed to insert a virtio device");
+            self.guest_macs.insert(
+                net_device.guest_mac,
+                utils::net_device_name(&net_device.config),
+            );
         }
 
         if !args.virblks.is_empty() {
@@ -199,6 +269,22 @@ impl CliInstance {
             }
         }
 
+        // guest network config of all the network devices
+        let net_config_disk = PathBuf::from(&args.net_config_disk);
+        if guest_net::write_config_disk(&net_devices, &net_config_disk)
+            .expect("failed to write the guest network config")
+        {
+            let net_config_info = BlockDeviceConfigInfo {
+                drive_id: String::from("netcfg"),
+                path_on_host: net_config_disk,
+                is_root_device: false,
+                is_read_only: true,
+                ..BlockDeviceConfigInfo::default()
+            };
+            self.insert_virblk(net_config_info)
+                .expect("failed to insert the guest network config disk");
+        }
+
         if !args.fs.is_empty() {
             let fs_config: FsDeviceConfigInfo = serde_json::from_str(&args.fs)
                 .expect("failed to parse virtio-fs devices from JSON");
@@ -206,6 +292,25 @@ impl CliInstance {
                 .expect("failed to insert a virtio-fs device");
         }
 
+        if !args.vhost_user_blks.is_empty() {
+            let configs: Vec<VhostUserBlkConfig> = serde_json::from_str(&args.vhost_user_blks)
+                .expect("failed to parse vhost-user-blk devices from JSON");
+            for config in configs.into_iter() {
+                self.insert_vhost_user_blk(config.into())
+                    .expect("failed to insert a vhost-user-blk device");
+            }
+        }
+
+        if !args.vhost_user_fs.is_empty() {
+            let fs_config: VhostUserFsConfig = serde_json::from_str(&args.vhost_user_fs)
+                .expect("failed to parse vhost-user-fs device from JSON");
+            self.insert_fs(fs_config.into())
+                .expect("failed to insert a vhost-user-fs device");
+        }
+
+        // the artifacts are opened when the VM starts.
+        self.measured_digests.check_unchanged()?;
+
         // start micro-vm
         self.instance_start().expect("failed to start micro-vm");
 
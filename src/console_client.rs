@@ -0,0 +1,152 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use anyhow::{anyhow, Context, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+
+use crate::console_server::ro_sock_path;
+use crate::parser::args::ConsoleArgs;
+use crate::runtime_dir;
+
+/// Ctrl-], the first key of the detach sequence.
+const ESCAPE_KEY: u8 = 0x1d;
+/// The key following the escape key to detach.
+const DETACH_KEY: u8 = b'q';
+const CONSOLE_BUF_SIZE: usize = 4096;
+
+/// Put the terminal into raw mode, and restore it on drop, which also happens
+/// when unwinding from a panic.
+pub struct RawTerminal {
+    fd: RawFd,
+    saved: Termios,
+}
+
+impl RawTerminal {
+    /// Returns None if `fd` is not a terminal.
+    pub fn new(fd: RawFd) -> Result<Option<Self>> {
+        if !nix::unistd::isatty(fd).unwrap_or(false) {
+            return Ok(None);
+        }
+        let saved = tcgetattr(fd).context("Failed to get the terminal attributes")?;
+        let mut raw = saved.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(fd, SetArg::TCSANOW, &raw).context("Failed to set the terminal to raw mode")?;
+        Ok(Some(RawTerminal { fd, saved }))
+    }
+
+    /// Also restore the terminal when any thread panics, as a panic outside
+    /// of the thread owning the `RawTerminal` does not drop it.
+    pub fn restore_on_panic(&self) {
+        let fd = self.fd;
+        let saved: libc::termios = self.saved.clone().into();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // SAFETY: tcsetattr only reads the termios struct we own.
+            unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+            default_hook(info);
+        }));
+    }
+
+    /// Also restore the terminal when dbs-cli exits without dropping the
+    /// `RawTerminal`.
+    pub fn restore_on_exit(&self) {
+        let fd = self.fd;
+        let saved = self.saved.clone();
+        runtime_dir::on_exit(move || {
+            let _ = tcsetattr(fd, SetArg::TCSANOW, &saved);
+        });
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSANOW, &self.saved);
+    }
+}
+
+/// Attach the local terminal to the console of a VM.
+pub fn run_console_client(args: ConsoleArgs) -> Result<()> {
+    match args.serial_path.as_str() {
+        "" => return Err(anyhow!("--serial-path or --name is required")),
+        "stdio" => return Err(anyhow!("the console of the VM is on the stdio of dbs-cli")),
+        _ => {}
+    }
+    let sock_path = if args.read_only {
+        ro_sock_path(&args.serial_path)
+    } else {
+        args.serial_path.clone()
+    };
+    let mut stream = UnixStream::connect(&sock_path)
+        .with_context(|| format!("Failed to connect to the console {sock_path}"))?;
+
+    let stdin = std::io::stdin();
+    let stdin_fd = stdin.as_raw_fd();
+    println!("dbs-cli: connected to {sock_path}, press Ctrl-] q to detach.");
+
+    let terminal = RawTerminal::new(stdin_fd)?;
+
+    let result = attach(&mut stream, stdin_fd, args.read_only);
+    drop(terminal);
+    println!();
+    result
+}
+
+fn attach(stream: &mut UnixStream, stdin_fd: RawFd, read_only: bool) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    let mut buf = [0u8; CONSOLE_BUF_SIZE];
+    let mut escape_pending = false;
+
+    loop {
+        let mut fds = [
+            PollFd::new(stream.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(stdin_fd, PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, -1) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            result => result.context("Failed to poll the console")?,
+        };
+
+        if !fds[0].revents().unwrap_or_else(PollFlags::empty).is_empty() {
+            let count = stream.read(&mut buf)?;
+            if count == 0 {
+                return Ok(());
+            }
+            stdout.write_all(&buf[..count])?;
+            stdout.flush()?;
+        }
+
+        if !fds[1].revents().unwrap_or_else(PollFlags::empty).is_empty() {
+            // read stdin unbuffered, as buffered input would not wake up poll.
+            let count = nix::unistd::read(stdin_fd, &mut buf)?;
+            if count == 0 {
+                return Ok(());
+            }
+            let mut input = Vec::with_capacity(count + 1);
+            for &byte in buf[..count].iter() {
+                if escape_pending {
+                    escape_pending = false;
+                    if byte == DETACH_KEY {
+                        return Ok(());
+                    }
+                    // Ctrl-] twice sends a single one to the guest.
+                    input.push(ESCAPE_KEY);
+                    if byte != ESCAPE_KEY {
+                        input.push(byte);
+                    }
+                } else if byte == ESCAPE_KEY {
+                    escape_pending = true;
+                } else {
+                    input.push(byte);
+                }
+            }
+            if !read_only {
+                stream.write_all(&input)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+
+use crate::guest_panic::{GuestPanicHandler, PANIC_BANNER};
+use crate::parser::args::ConsoleLogArgs;
+use crate::utils;
+
+/// The guest console output file, rotated by size.
+struct ConsoleLogFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+    timestamps: bool,
+    /// whether the next byte starts a line, which gets a timestamp
+    at_line_start: bool,
+}
+
+impl ConsoleLogFile {
+    fn open(args: &ConsoleLogArgs, path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open console log file {path}"))?;
+        let size = file.metadata()?.len();
+        Ok(ConsoleLogFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_size: args.console_log_max_size_kb * 1024,
+            max_files: args.console_log_max_files,
+            timestamps: args.console_log_timestamps,
+            at_line_start: true,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut out = Vec::with_capacity(data.len());
+        for line in data.split_inclusive(|&byte| byte == b'\n') {
+            if self.timestamps && self.at_line_start {
+                out.extend_from_slice(format!("[{}] ", utils::utc_timestamp()).as_bytes());
+            }
+            out.extend_from_slice(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+
+        if self.max_size > 0 && self.size + out.len() as u64 > self.max_size && self.size > 0 {
+            self.rotate()?;
+        }
+        self.file.write_all(&out)?;
+        self.size += out.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `path.1` .. `path.N-1` by one, move `path` to `path.1` and start
+    /// a new file. Without rotated files, the file is truncated.
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if std::path::Path::new(&from).exists() {
+                    std::fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Longer console lines are cut when watching for the guest panic banner.
+const MAX_LINE_SIZE: usize = 1024;
+
+struct ConsoleLogInner {
+    ring: VecDeque<u8>,
+    ring_size: usize,
+    file: Option<ConsoleLogFile>,
+    /// the current line, to watch for the guest panic banner
+    line: Vec<u8>,
+    panic_handler: Option<Arc<GuestPanicHandler>>,
+}
+
+impl ConsoleLogInner {
+    /// Return the first complete line of `data` with the guest panic banner.
+    fn find_panic(&mut self, data: &[u8]) -> Option<String> {
+        let mut banner = None;
+        for chunk in data.split_inclusive(|&byte| byte == b'\n') {
+            if self.line.len() < MAX_LINE_SIZE {
+                self.line.extend_from_slice(chunk);
+            }
+            if chunk.ends_with(b"\n") {
+                if banner.is_none()
+                    && self
+                        .line
+                        .windows(PANIC_BANNER.len())
+                        .any(|window| window == PANIC_BANNER)
+                {
+                    banner = Some(String::from_utf8_lossy(&self.line).into_owned());
+                }
+                self.line.clear();
+            }
+        }
+        banner
+    }
+}
+
+/// The guest console output, written to a file and kept in a ring buffer of
+/// the last bytes, so that it is still around after a guest panic.
+#[derive(Clone)]
+pub struct ConsoleLog {
+    inner: Arc<Mutex<ConsoleLogInner>>,
+}
+
+impl ConsoleLog {
+    pub fn new(args: &ConsoleLogArgs) -> Result<Self> {
+        let file = match &args.console_log_file {
+            Some(path) => Some(ConsoleLogFile::open(args, path)?),
+            None => None,
+        };
+        let ring_size = args.console_ring_size_kb * 1024;
+        Ok(ConsoleLog {
+            inner: Arc::new(Mutex::new(ConsoleLogInner {
+                ring: VecDeque::with_capacity(ring_size),
+                ring_size,
+                file,
+                line: vec![],
+                panic_handler: None,
+            })),
+        })
+    }
+
+    /// Handle the guest kernel panic seen on the console with `panic_handler`.
+    pub fn set_panic_handler(&self, panic_handler: Arc<GuestPanicHandler>) {
+        self.inner.lock().unwrap().panic_handler = Some(panic_handler);
+    }
+
+    /// Record a chunk of the guest console output.
+    pub fn record(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let ring_size = inner.ring_size;
+        let data_in_ring = &data[data.len().saturating_sub(ring_size)..];
+        let overflow = (inner.ring.len() + data_in_ring.len()).saturating_sub(ring_size);
+        inner.ring.drain(..overflow);
+        inner.ring.extend(data_in_ring);
+
+        if let Some(file) = inner.file.as_mut() {
+            if let Err(err) = file.write(data) {
+                log::warn!(
+                    "Failed to write console log file {}, stop writing it: {:?}",
+                    file.path,
+                    err
+                );
+                inner.file = None;
+            }
+        }
+
+        // the handler may take a while, so it runs without the lock.
+        if let Some(banner) = inner.find_panic(data) {
+            if let Some(panic_handler) = inner.panic_handler.clone() {
+                let console: Vec<u8> = inner.ring.iter().copied().collect();
+                drop(inner);
+                panic_handler.handle(&banner, &console);
+            }
+        }
+    }
+
+    /// Get the last `size` bytes of the console output in the ring buffer.
+    pub fn tail(&self, size: usize) -> Vec<u8> {
+        let inner = self.inner.lock().unwrap();
+        let skip = inner.ring.len().saturating_sub(size);
+        inner.ring.iter().skip(skip).copied().collect()
+    }
+}
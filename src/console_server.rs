@@ -0,0 +1,227 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::console_log::ConsoleLog;
+use crate::runtime_dir;
+
+const CONSOLE_BUF_SIZE: usize = 4096;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// The console output a client may lag behind, beyond which it is
+/// disconnected rather than stalling the console.
+const CLIENT_BUF_SIZE: usize = 256 << 10;
+
+/// The socket the Dragonball serial console listens on, for a console served
+/// on `sock_path`.
+pub fn vmm_sock_path(sock_path: &str) -> String {
+    format!("{sock_path}.vmm")
+}
+
+/// The socket read-only observers of the console attach to.
+pub fn ro_sock_path(sock_path: &str) -> String {
+    format!("{sock_path}.ro")
+}
+
+struct Client {
+    stream: UnixStream,
+    writable: bool,
+    /// the console output not written to the client yet
+    pending: Vec<u8>,
+}
+
+impl Client {
+    /// Write the pending output as far as the client takes it without
+    /// blocking.
+    fn flush(&mut self) -> std::io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(count) => {
+                    self.pending.drain(..count);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Share the guest serial console among several clients.
+///
+/// The Dragonball serial console takes a single connection, so dbs-cli
+/// connects to it and serves the console on `sock_path` to a single writer,
+/// while any number of read-only observers can attach to the `.ro` socket.
+/// A second writer is refused.
+pub struct ConsoleServer {
+    sock_path: String,
+    clients: Vec<Client>,
+    console_log: ConsoleLog,
+}
+
+impl ConsoleServer {
+    pub fn new(sock_path: String, console_log: ConsoleLog) -> Self {
+        ConsoleServer {
+            sock_path,
+            clients: vec![],
+            console_log,
+        }
+    }
+
+    /// Remove the sockets left by a previous run, and the ones of this run on
+    /// exit.
+    fn remove_sockets(&self) -> Result<()> {
+        for path in [self.sock_path.clone(), ro_sock_path(&self.sock_path)] {
+            runtime_dir::remove_stale_socket(&path)?;
+            runtime_dir::register(&path);
+        }
+        Ok(())
+    }
+
+    /// Connect to the serial console socket, which is created when the VM
+    /// starts. The clients are served in the meantime, and the connection
+    /// is retried every CONNECT_RETRY_INTERVAL.
+    fn connect_vmm(&self) -> Option<UnixStream> {
+        UnixStream::connect(vmm_sock_path(&self.sock_path)).ok()
+    }
+
+    pub fn run_console_server(&mut self) -> Result<()> {
+        self.remove_sockets()?;
+        let rw_listener = UnixListener::bind(&self.sock_path)
+            .with_context(|| format!("Failed to bind console socket {}", self.sock_path))?;
+        let ro_sock_path = ro_sock_path(&self.sock_path);
+        let ro_listener = UnixListener::bind(&ro_sock_path)
+            .with_context(|| format!("Failed to bind console socket {ro_sock_path}"))?;
+
+        let mut vmm = self.connect_vmm();
+        let mut buf = [0u8; CONSOLE_BUF_SIZE];
+        loop {
+            if vmm.is_none() {
+                vmm = self.connect_vmm();
+            }
+            // poll ignores the negative fd while the VM is not connected.
+            let mut fds = vec![
+                PollFd::new(
+                    vmm.as_ref().map_or(-1, |vmm| vmm.as_raw_fd()),
+                    PollFlags::POLLIN,
+                ),
+                PollFd::new(rw_listener.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(ro_listener.as_raw_fd(), PollFlags::POLLIN),
+            ];
+            for client in self.clients.iter() {
+                let mut events = PollFlags::POLLIN;
+                if !client.pending.is_empty() {
+                    events |= PollFlags::POLLOUT;
+                }
+                fds.push(PollFd::new(client.stream.as_raw_fd(), events));
+            }
+            let timeout = if vmm.is_some() {
+                -1
+            } else {
+                CONNECT_RETRY_INTERVAL.as_millis() as i32
+            };
+            match poll(&mut fds, timeout) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                result => result.context("Failed to poll the console sockets")?,
+            };
+            let ready: Vec<PollFlags> = fds
+                .iter()
+                .map(|fd| fd.revents().unwrap_or_else(PollFlags::empty))
+                .collect();
+
+            // client input goes first, so that the indexes match the polled fds.
+            let mut closed = vec![];
+            for (i, client) in self.clients.iter_mut().enumerate() {
+                let revents = ready[i + 3];
+                if revents.contains(PollFlags::POLLOUT) && client.flush().is_err() {
+                    closed.push(i);
+                    continue;
+                }
+                if revents.is_empty() || revents == PollFlags::POLLOUT {
+                    continue;
+                }
+                match client.stream.read(&mut buf) {
+                    Ok(count) if count > 0 => {
+                        // the input while the VM is not connected is dropped.
+                        let written = match vmm.as_mut() {
+                            Some(stream) if client.writable => {
+                                stream.write_all(&buf[..count]).is_ok()
+                            }
+                            _ => true,
+                        };
+                        if !written {
+                            vmm = None;
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    _ => closed.push(i),
+                }
+            }
+            for i in closed.into_iter().rev() {
+                self.clients.remove(i);
+            }
+
+            if let Some(stream) = vmm.as_mut().filter(|_| !ready[0].is_empty()) {
+                match stream.read(&mut buf) {
+                    Ok(count) if count > 0 => {
+                        self.console_log.record(&buf[..count]);
+                        self.broadcast(&buf[..count]);
+                    }
+                    // the VM is gone, the clients stay for a restarted one.
+                    _ => vmm = None,
+                }
+            }
+
+            if !ready[1].is_empty() {
+                let (stream, _) = rw_listener.accept()?;
+                self.add_client(stream, true);
+            }
+            if !ready[2].is_empty() {
+                let (stream, _) = ro_listener.accept()?;
+                self.add_client(stream, false);
+            }
+        }
+    }
+
+    fn add_client(&mut self, mut stream: UnixStream, writable: bool) {
+        if writable && self.clients.iter().any(|client| client.writable) {
+            let _ = write!(
+                stream,
+                "dbs-cli: the console already has a writer, attach read-only to {}\r\n",
+                ro_sock_path(&self.sock_path)
+            );
+            return;
+        }
+        if stream.set_nonblocking(true).is_ok() {
+            self.clients.push(Client {
+                stream,
+                writable,
+                pending: vec![],
+            });
+        }
+    }
+
+    /// Send the console output to all the clients without blocking, dropping
+    /// the ones which lag behind by more than CLIENT_BUF_SIZE.
+    fn broadcast(&mut self, data: &[u8]) {
+        self.clients.retain_mut(|client| {
+            client.pending.extend_from_slice(data);
+            if client.flush().is_err() {
+                return false;
+            }
+            if client.pending.len() > CLIENT_BUF_SIZE {
+                log::warn!("dropped a console client which cannot keep up with the output");
+                return false;
+            }
+            true
+        });
+    }
+}
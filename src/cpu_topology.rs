@@ -0,0 +1,160 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+
+use crate::parser::args::CpuArgs;
+
+/// The `--cpu-pm` values of Dragonball.
+const CPU_PM_VALUES: &[&str] = &["on", "off"];
+/// The highest `--vpmu-feature` level of Dragonball, 0 disables the vPMU.
+const MAX_VPMU_FEATURE: u8 = 2;
+
+/// Check the vCPUs and the CPU topology against each other and against the
+/// `kvm_max_vcpus` KVM supports per VM, KVM_CAP_MAX_VCPUS, before they are sent to Dragonball.
+///
+/// Without topology options, the topology is derived from `--max-vcpu` as
+/// one socket of single-threaded cores.
+pub fn validate(args: &mut CpuArgs, kvm_max_vcpus: usize) -> Result<()> {
+    if !CPU_PM_VALUES.contains(&args.cpu_pm.as_str()) {
+        return Err(anyhow!(
+            "invalid --cpu-pm {:?}, expected on or off",
+            args.cpu_pm
+        ));
+    }
+    if args.vpmu_feature > MAX_VPMU_FEATURE {
+        return Err(anyhow!(
+            "invalid --vpmu-feature {}, expected 0 to {MAX_VPMU_FEATURE}",
+            args.vpmu_feature
+        ));
+    }
+    if args.vcpu == 0 {
+        return Err(anyhow!("--vcpu has to be at least 1"));
+    }
+    if args.vcpu > args.max_vcpu {
+        return Err(anyhow!(
+            "--vcpu {} exceeds --max-vcpu {}, pass --max-vcpu {} or more",
+            args.vcpu,
+            args.max_vcpu,
+            args.vcpu
+        ));
+    }
+    if args.max_vcpu as usize > kvm_max_vcpus {
+        return Err(anyhow!(
+            "--max-vcpu {} exceeds the {} vCPUs KVM supports per VM",
+            args.max_vcpu,
+            kvm_max_vcpus
+        ));
+    }
+
+    let topology = &mut args.cpu_topology;
+    let levels = [
+        ("--threads-per-core", topology.threads_per_core),
+        ("--cores-per-die", topology.cores_per_die),
+        ("--dies-per-socket", topology.dies_per_socket),
+        ("--sockets", topology.sockets),
+    ];
+    if let Some((name, _)) = levels.iter().find(|(_, count)| *count == 0) {
+        return Err(anyhow!("{name} has to be at least 1"));
+    }
+    if topology.threads_per_core > 2 {
+        return Err(anyhow!(
+            "invalid --threads-per-core {}, expected 1 or 2",
+            topology.threads_per_core
+        ));
+    }
+    let product: u32 = levels.iter().map(|(_, count)| *count as u32).product();
+    // the topology options are all left at 1.
+    if product == 1 && args.max_vcpu > 1 {
+        topology.cores_per_die = args.max_vcpu;
+        return Ok(());
+    }
+    if product != args.max_vcpu as u32 {
+        return Err(anyhow!(
+            "the CPU topology of {} threads per core, {} cores per die, {} dies per socket and {} sockets has {} vCPUs, not --max-vcpu {}",
+            topology.threads_per_core,
+            topology.cores_per_die,
+            topology.dies_per_socket,
+            topology.sockets,
+            product,
+            args.max_vcpu
+        ));
+    }
+    Ok(())
+}
+
+/// Check the vCPU count of a `resize_vcpu` request against the max vCPUs of
+/// the VM.
+pub fn check_vcpu_resize(vcpu_count: Option<u64>, max_vcpu_count: u8) -> Result<u8> {
+    let vcpu_count =
+        vcpu_count.ok_or_else(|| anyhow!("the vCPU count to resize to is required"))?;
+    if vcpu_count == 0 || vcpu_count > max_vcpu_count as u64 {
+        return Err(anyhow!(
+            "cannot resize to {vcpu_count} vCPUs, expected 1 to the max vCPUs {max_vcpu_count} of the VM"
+        ));
+    }
+    Ok(vcpu_count as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[clap(flatten)]
+        cpu: CpuArgs,
+    }
+
+    fn validate_args(args: &[&str], kvm_max_vcpus: usize) -> Result<CpuArgs> {
+        let mut cpu =
+            TestCli::parse_from(std::iter::once("dbs-cli").chain(args.iter().copied())).cpu;
+        validate(&mut cpu, kvm_max_vcpus)?;
+        Ok(cpu)
+    }
+
+    #[test]
+    fn test_validate() {
+        let cpu = validate_args(&[], 8).unwrap();
+        assert_eq!(cpu.cpu_topology.cores_per_die, 1);
+        // the topology defaults to one socket of --max-vcpu cores.
+        let cpu = validate_args(&["--vcpu", "2", "--max-vcpu", "4"], 8).unwrap();
+        assert_eq!(cpu.cpu_topology.cores_per_die, 4);
+        let cpu = validate_args(
+            &[
+                "--max-vcpu",
+                "8",
+                "--threads-per-core",
+                "2",
+                "--cores-per-die",
+                "2",
+                "--sockets",
+                "2",
+            ],
+            8,
+        )
+        .unwrap();
+        assert_eq!(cpu.cpu_topology.cores_per_die, 2);
+
+        assert!(validate_args(&["--vcpu", "0"], 8).is_err());
+        assert!(validate_args(&["--vcpu", "2", "--max-vcpu", "1"], 8).is_err());
+        assert!(validate_args(&["--max-vcpu", "16"], 8).is_err());
+        assert!(validate_args(&["--cpu-pm", "maybe"], 8).is_err());
+        assert!(validate_args(&["--vpmu-feature", "3"], 8).is_err());
+        assert!(validate_args(&["--max-vcpu", "4", "--sockets", "0"], 8).is_err());
+        assert!(validate_args(&["--max-vcpu", "3", "--threads-per-core", "3"], 8).is_err());
+        assert!(validate_args(&["--max-vcpu", "6", "--sockets", "4"], 8).is_err());
+    }
+
+    #[test]
+    fn test_check_vcpu_resize() {
+        assert_eq!(check_vcpu_resize(Some(1), 4).unwrap(), 1);
+        assert_eq!(check_vcpu_resize(Some(4), 4).unwrap(), 4);
+        assert!(check_vcpu_resize(None, 4).is_err());
+        assert!(check_vcpu_resize(Some(0), 4).is_err());
+        assert!(check_vcpu_resize(Some(5), 4).is_err());
+        assert!(check_vcpu_resize(Some(u64::MAX), 4).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::{open, OFlag};
+use nix::sys::socket::{sendto, socket, AddressFamily, MsgFlags, SockFlag, SockType, UnixAddr};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup2, fork, pipe2, setsid, write, ForkResult};
+
+use crate::runtime_dir;
+
+const READY_MESSAGE: &[u8] = b"READY=1\n";
+
+/// Detach dbs-cli from the terminal, with its stdio redirected to /dev/null.
+///
+/// The parent process waits until the VM is ready, then exits with 0, or with
+/// 1 if the VM failed to start. Returns the fd of the child process to tell
+/// the parent that the VM is ready.
+pub fn daemonize() -> Result<RawFd> {
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).context("Failed to create a pipe")?;
+
+    // SAFETY: dbs-cli has a single thread yet.
+    match unsafe { fork() }.context("Failed to fork")? {
+        ForkResult::Parent { child } => {
+            let _ = close(write_fd);
+            let mut buf = [0u8; READY_MESSAGE.len()];
+            let ready = matches!(nix::unistd::read(read_fd, &mut buf), Ok(count) if count > 0);
+            if ready {
+                println!("dbs-cli: the VM is running in the background, pid {child}");
+                std::process::exit(0);
+            }
+            eprintln!("dbs-cli: the VM failed to start, see the log file");
+            std::process::exit(1);
+        }
+        ForkResult::Child => {
+            let _ = close(read_fd);
+            setsid().context("Failed to create a new session")?;
+            let null_fd = open("/dev/null", OFlag::O_RDWR, Mode::empty())
+                .context("Failed to open /dev/null")?;
+            for fd in 0..3 {
+                dup2(null_fd, fd).context("Failed to redirect stdio to /dev/null")?;
+            }
+            let _ = close(null_fd);
+            Ok(write_fd)
+        }
+    }
+}
+
+/// The pid file of dbs-cli, removed on drop.
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write the pid file {path}"))?;
+        runtime_dir::register(path);
+        Ok(PidFile {
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+struct ReadinessInner {
+    vm_started: bool,
+    api_listening: bool,
+    notified: bool,
+    ready_fds: Vec<RawFd>,
+}
+
+/// Tell the service manager that the VM is ready, once `instance_start` has
+/// succeeded and the API server is listening.
+///
+/// The readiness is sent to `NOTIFY_SOCKET` as in `sd_notify(3)`, written to
+/// the fds in `ready_fds`, which are closed then, and to the parent process
+/// of a daemon.
+#[derive(Clone)]
+pub struct Readiness {
+    inner: Arc<Mutex<ReadinessInner>>,
+}
+
+impl Readiness {
+    pub fn new(ready_fds: Vec<RawFd>) -> Self {
+        Readiness {
+            inner: Arc::new(Mutex::new(ReadinessInner {
+                vm_started: false,
+                api_listening: false,
+                notified: false,
+                ready_fds,
+            })),
+        }
+    }
+
+    pub fn set_vm_started(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.vm_started = true;
+        Self::notify(&mut inner);
+    }
+
+    pub fn set_api_listening(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.api_listening = true;
+        Self::notify(&mut inner);
+    }
+
+    /// Close the fds without readiness, as the VM failed to start.
+    pub fn set_failed(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.notified {
+            return;
+        }
+        inner.notified = true;
+        for fd in inner.ready_fds.drain(..) {
+            let _ = close(fd);
+        }
+    }
+
+    fn notify(inner: &mut ReadinessInner) {
+        if inner.notified || !inner.vm_started || !inner.api_listening {
+            return;
+        }
+        inner.notified = true;
+
+        for fd in inner.ready_fds.drain(..) {
+            if let Err(err) = write(fd, READY_MESSAGE) {
+                log::warn!("Failed to write the readiness to fd {}: {:?}", fd, err);
+            }
+            let _ = close(fd);
+        }
+        if let Ok(notify_socket) = std::env::var("NOTIFY_SOCKET") {
+            if let Err(err) = sd_notify(&notify_socket) {
+                log::warn!("Failed to notify the readiness to systemd: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Send `READY=1` to the systemd notify socket, which is in the abstract
+/// namespace if it starts with `@`.
+fn sd_notify(notify_socket: &str) -> Result<()> {
+    let addr = match notify_socket.strip_prefix('@') {
+        Some(name) => UnixAddr::new_abstract(name.as_bytes())?,
+        None if notify_socket.starts_with('/') => UnixAddr::new(notify_socket)?,
+        None => return Err(anyhow!("invalid NOTIFY_SOCKET {notify_socket}")),
+    };
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let message = format!("READY=1\nMAINPID={}", std::process::id());
+    let result = sendto(fd, message.as_bytes(), &addr, MsgFlags::empty());
+    let _ = close(fd);
+    result?;
+    Ok(())
+}
@@ -0,0 +1,485 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, Metadata};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::parser::args::CreateArgs;
+
+/// The digest of a boot artifact, measured before the VM boots.
+#[derive(Clone)]
+struct Measurement {
+    /// kernel, initrd, rootfs or the drive ID of a drive of --virblks
+    artifact: String,
+    path: String,
+    sha256: String,
+    /// whether it matched an expected digest
+    verified: bool,
+    /// the file which was measured
+    identity: FileIdentity,
+}
+
+/// What tells a file apart from another one, or from itself modified: the
+/// device, the inode, the size and the modification time.
+#[derive(Clone, PartialEq, Eq)]
+struct FileIdentity {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+impl From<&Metadata> for FileIdentity {
+    fn from(metadata: &Metadata) -> Self {
+        FileIdentity {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        }
+    }
+}
+
+/// The digests measured at the last boot of the VM, shared with the API
+/// server for the `get_digests` query.
+#[derive(Clone, Default)]
+pub struct MeasuredDigests(Arc<Mutex<Vec<Measurement>>>);
+
+impl MeasuredDigests {
+    pub fn query(&self) -> Value {
+        let artifacts: Vec<Value> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|measurement| {
+                json!({
+                    "artifact": measurement.artifact,
+                    "path": measurement.path,
+                    "sha256": measurement.sha256,
+                    "verified": measurement.verified,
+                })
+            })
+            .collect();
+        json!({ "artifacts": artifacts })
+    }
+
+    /// Check that the measured files are still the ones measured, right
+    /// before the VMM opens them.
+    ///
+    /// The VMM opens the files by path, so a file replaced or modified after
+    /// this check is not caught, nor is a drive written to while the VM
+    /// runs. The boot artifacts are expected to be read-only to anybody but
+    /// their owner.
+    pub fn check_unchanged(&self) -> Result<()> {
+        for measurement in self.0.lock().unwrap().iter() {
+            let unchanged = std::fs::metadata(&measurement.path)
+                .map(|metadata| FileIdentity::from(&metadata) == measurement.identity)
+                .unwrap_or(false);
+            if !unchanged {
+                return Err(anyhow!(
+                    "the {} {} changed since it was measured, refusing to boot",
+                    measurement.artifact,
+                    measurement.path
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the expected digests, and take the ones of the boot artifacts from
+/// `--digest-manifest`, which has to list all of them and be signed with the
+/// trusted key.
+pub fn resolve(args: &mut CreateArgs) -> Result<()> {
+    let drives = drive_paths(args)?;
+    let digest = &mut args.digest;
+    let manifest = match (
+        digest.digest_manifest.take(),
+        digest.digest_manifest_sig.take(),
+        digest.digest_manifest_key.take(),
+    ) {
+        (Some(manifest), Some(sig), Some(key)) => Some((manifest, sig, key)),
+        (None, None, None) => None,
+        (Some(_), _, _) => {
+            return Err(anyhow!(
+                "--digest-manifest requires --digest-manifest-sig and --digest-manifest-key"
+            ))
+        }
+        (None, _, _) => {
+            return Err(anyhow!(
+                "--digest-manifest-sig and --digest-manifest-key require --digest-manifest"
+            ))
+        }
+    };
+    if let Some((manifest, sig, key)) = manifest {
+        let entries = read_manifest(&manifest, &sig, &key)?;
+        let lookup = |path: &str| {
+            entries
+                .iter()
+                .find(|(listed, _)| same_file(listed, path))
+                .map(|(_, sha256)| sha256.clone())
+                .ok_or_else(|| anyhow!("{path} is not listed in the digest manifest {manifest}"))
+        };
+        if let (None, Some(kernel_path)) = (&digest.kernel_sha256, &args.kernel_path) {
+            digest.kernel_sha256 = Some(lookup(kernel_path)?);
+        }
+        if let (None, Some(initrd_path)) = (&digest.initrd_sha256, &args.initrd_path) {
+            digest.initrd_sha256 = Some(lookup(initrd_path)?);
+        }
+        for (_, path) in drives.iter() {
+            if !digest
+                .drive_sha256
+                .iter()
+                .any(|(drive, _)| same_file(drive, path))
+            {
+                digest.drive_sha256.push((path.clone(), lookup(path)?));
+            }
+        }
+    }
+
+    for sha256 in [&mut digest.kernel_sha256, &mut digest.initrd_sha256]
+        .into_iter()
+        .flatten()
+        .chain(digest.drive_sha256.iter_mut().map(|(_, sha256)| sha256))
+    {
+        *sha256 = parse_sha256(sha256)?;
+    }
+    // a digest of a drive the VM does not have is a mistake.
+    for (path, _) in digest.drive_sha256.iter() {
+        if !drives.iter().any(|(_, drive)| same_file(drive, path)) {
+            return Err(anyhow!(
+                "{path} of --drive-sha256 is neither the rootfs nor a drive of --virblks"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Measure the kernel, the initrd and the drives with expected digests, and
+/// refuse to boot on a mismatch. The digests go to the log as evidence of
+/// what the VM booted.
+pub fn verify(args: &CreateArgs, measured: &MeasuredDigests) -> Result<()> {
+    let digest = &args.digest;
+    let mut artifacts = vec![];
+    if let Some(kernel_path) = &args.kernel_path {
+        artifacts.push((
+            "kernel".to_string(),
+            kernel_path.clone(),
+            digest.kernel_sha256.clone(),
+        ));
+    }
+    if let Some(initrd_path) = &args.initrd_path {
+        artifacts.push((
+            "initrd".to_string(),
+            initrd_path.clone(),
+            digest.initrd_sha256.clone(),
+        ));
+    }
+    for (artifact, path) in drive_paths(args)? {
+        // the drives are large, only the ones with digests are measured.
+        if let Some((_, sha256)) = digest
+            .drive_sha256
+            .iter()
+            .find(|(drive, _)| same_file(drive, &path))
+        {
+            artifacts.push((artifact, path, Some(sha256.clone())));
+        }
+    }
+
+    let measurements = artifacts
+        .into_iter()
+        .map(|(artifact, path, expected)| measure(artifact, path, expected, "boot"))
+        .collect::<Result<_>>()?;
+    *measured.0.lock().unwrap() = measurements;
+    Ok(())
+}
+
+/// Measure the drives of an `insert_virblks` request, as (drive ID, path),
+/// which have a digest in `expected`, as (path, digest), and refuse to
+/// insert them on a mismatch. The measurements are added to the ones of the
+/// boot.
+pub fn verify_hotplug(
+    drives: &[(String, String)],
+    expected: &[(String, String)],
+    measured: &MeasuredDigests,
+) -> Result<()> {
+    for (path, _) in expected.iter() {
+        if !drives.iter().any(|(_, drive)| same_file(drive, path)) {
+            return Err(anyhow!(
+                "{path} of drive_sha256 is not a drive of the request"
+            ));
+        }
+    }
+    let mut measurements = vec![];
+    for (drive_id, path) in drives.iter() {
+        if let Some((_, sha256)) = expected.iter().find(|(listed, _)| same_file(listed, path)) {
+            let sha256 = parse_sha256(sha256)?;
+            measurements.push(measure(
+                drive_id.clone(),
+                path.clone(),
+                Some(sha256),
+                "insert it",
+            )?);
+        }
+    }
+    measured.0.lock().unwrap().extend(measurements);
+    Ok(())
+}
+
+/// Measure an artifact, refusing to `action` if it does not match the
+/// expected digest.
+fn measure(
+    artifact: String,
+    path: String,
+    expected: Option<String>,
+    action: &str,
+) -> Result<Measurement> {
+    let (sha256, identity) = sha256_file(&path)?;
+    if let Some(expected) = &expected {
+        if *expected != sha256 {
+            return Err(anyhow!(
+                "the {artifact} {path} has the SHA-256 digest {sha256}, not {expected}, refusing to {action}"
+            ));
+        }
+    }
+    log::info!(
+        "measured the {} {}: sha256 {}{}",
+        artifact,
+        path,
+        sha256,
+        if expected.is_some() { ", verified" } else { "" }
+    );
+    Ok(Measurement {
+        artifact,
+        path,
+        sha256,
+        verified: expected.is_some(),
+        identity,
+    })
+}
+
+/// The rootfs and the drives of --virblks, as (artifact, path).
+fn drive_paths(args: &CreateArgs) -> Result<Vec<(String, String)>> {
+    let mut drives = vec![];
+    if let Some(rootfs) = &args.rootfs_args.rootfs {
+        drives.push(("rootfs".to_string(), rootfs.clone()));
+    }
+    if !args.virblks.is_empty() {
+        let configs: Vec<Value> = serde_json::from_str(&args.virblks)
+            .with_context(|| format!("Invalid virtio-blk config {}", args.virblks))?;
+        for config in configs.iter() {
+            if let Some(path) = config["path_on_host"].as_str() {
+                let drive_id = config["drive_id"].as_str().unwrap_or("virblk");
+                drives.push((drive_id.to_string(), path.to_string()));
+            }
+        }
+    }
+    Ok(drives)
+}
+
+/// The entries of a manifest in the sha256sum format, as (path, digest), with
+/// the relative paths resolved against the directory of the manifest.
+/// Read a digest manifest, once its signature `sig` is verified with the
+/// public key `key`. The content verified is the one parsed.
+fn read_manifest(manifest: &str, sig: &str, key: &str) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read(manifest)
+        .with_context(|| format!("Failed to read the digest manifest {manifest}"))?;
+    verify_signature(manifest, &content, sig, key)?;
+    let content = String::from_utf8(content)
+        .with_context(|| format!("Invalid content of the digest manifest {manifest}"))?;
+    parse_manifest(manifest, &content)
+}
+
+/// Verify the signature `sig` of `content` with the public key `key`, with
+/// `openssl dgst`, which takes RSA and ECDSA keys.
+fn verify_signature(manifest: &str, content: &[u8], sig: &str, key: &str) -> Result<()> {
+    let mut child = Command::new("openssl")
+        .args(["dgst", "-sha256", "-verify", key, "-signature", sig])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to execute openssl")?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(content)
+        .context("Failed to pass the digest manifest to openssl")?;
+    let output = child
+        .wait_with_output()
+        .context("Failed to wait for openssl")?;
+    if !output.status.success() {
+        // a bad signature is reported on stdout, a bad key or file on stderr.
+        let message = if output.stdout.is_empty() {
+            &output.stderr
+        } else {
+            &output.stdout
+        };
+        return Err(anyhow!(
+            "the signature {} of the digest manifest {} does not verify with {}: {}",
+            sig,
+            manifest,
+            key,
+            String::from_utf8_lossy(message).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// The entries of a digest manifest, as (path, digest), with the paths
+/// relative to the directory of the manifest.
+fn parse_manifest(manifest: &str, content: &str) -> Result<Vec<(String, String)>> {
+    let dir = Path::new(manifest).parent().unwrap_or(Path::new(""));
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (sha256, path) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                anyhow!("invalid line {line:?} in the digest manifest {manifest}")
+            })?;
+            // the binary mode of sha256sum marks the path with '*'.
+            let path = path.trim_start().trim_start_matches('*');
+            Ok((
+                dir.join(path).to_string_lossy().into_owned(),
+                sha256.to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn parse_sha256(sha256: &str) -> Result<String> {
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid SHA-256 digest {sha256:?}"));
+    }
+    Ok(sha256.to_ascii_lowercase())
+}
+
+/// Whether the paths are the same file, or the same paths if they do not
+/// exist.
+fn same_file(a: &str, b: &str) -> bool {
+    let canonical =
+        |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    canonical(a) == canonical(b)
+}
+
+/// The SHA-256 digest of a file, with the identity of the file measured,
+/// which must not change while it is read.
+fn sha256_file(path: &str) -> Result<(String, FileIdentity)> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    let identity = |file: &File| -> Result<FileIdentity> {
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to stat {path}"))?;
+        Ok(FileIdentity::from(&metadata))
+    };
+    let before = identity(&file)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {path}"))?;
+    if identity(&file)? != before {
+        return Err(anyhow!("{path} was modified while it was measured"));
+    }
+    Ok((format!("{:x}", hasher.finalize()), before))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sha256() {
+        let sha256 = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert_eq!(parse_sha256(sha256).unwrap(), sha256.to_ascii_lowercase());
+        assert!(parse_sha256("").is_err());
+        assert!(parse_sha256(&sha256[1..]).is_err());
+        assert!(parse_sha256(&format!("{}0", sha256)).is_err());
+        assert!(parse_sha256(&sha256.replace('E', "g")).is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let dir = std::env::temp_dir().join(format!("dbs-cli-test-{}", std::process::id()));
+        let manifest = dir.join("artifacts.sha256");
+        let manifest = manifest.to_string_lossy();
+        let entries = parse_manifest(
+            &manifest,
+            "# boot artifacts\n\
+             aaaa  vmlinux.bin\n\
+             \n\
+             bbbb *rootfs.ext4\n\
+             cccc  /abs/drive.img\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    dir.join("vmlinux.bin").to_string_lossy().into_owned(),
+                    "aaaa".to_string()
+                ),
+                (
+                    dir.join("rootfs.ext4").to_string_lossy().into_owned(),
+                    "bbbb".to_string()
+                ),
+                ("/abs/drive.img".to_string(), "cccc".to_string()),
+            ]
+        );
+
+        assert!(parse_manifest(&manifest, "aaaa\n").is_err());
+    }
+
+    #[test]
+    fn test_read_manifest() {
+        let dir = std::env::temp_dir().join(format!("dbs-cli-sig-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let (manifest, sig, key, pubkey) = (
+            path("artifacts.sha256"),
+            path("artifacts.sha256.sig"),
+            path("key.pem"),
+            path("pubkey.pem"),
+        );
+        std::fs::write(&manifest, "aaaa  vmlinux.bin\n").unwrap();
+        let openssl = |args: &[&str]| {
+            Command::new("openssl")
+                .args(args)
+                .output()
+                .map(|output| output.status.success())
+                .unwrap_or(false)
+        };
+        // openssl is not installed.
+        if !openssl(&[
+            "genpkey",
+            "-algorithm",
+            "EC",
+            "-pkeyopt",
+            "ec_paramgen_curve:P-256",
+            "-out",
+            &key,
+        ]) {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        assert!(openssl(&["pkey", "-in", &key, "-pubout", "-out", &pubkey]));
+        assert!(openssl(&[
+            "dgst", "-sha256", "-sign", &key, "-out", &sig, &manifest
+        ]));
+
+        let entries = read_manifest(&manifest, &sig, &pubkey).unwrap();
+        assert_eq!(entries, vec![(path("vmlinux.bin"), "aaaa".to_string())]);
+
+        std::fs::write(&manifest, "bbbb  vmlinux.bin\n").unwrap();
+        assert!(read_manifest(&manifest, &sig, &pubkey).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(read_manifest(&manifest, &sig, &pubkey).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io::Write;
+
+use slog::{Drain, Key, Level, OwnedKVList, Record, Serializer, KV};
+
+use crate::utils::LogFile;
+
+/// The subsystem of the records Dragonball logs for the second serial port.
+const DMESG_SUBSYSTEM: &str = "dmesg";
+/// Kernel log levels, as in `<3>` prefixes of printk.
+const KERN_ERR: u8 = 3;
+const KERN_WARNING: u8 = 4;
+
+/// Markers of guest kernel messages that are errors, for the lines without a
+/// log level prefix as printed on a serial console.
+const KERN_ERR_MARKERS: [&str; 5] = [
+    "Kernel panic",
+    "BUG:",
+    "Oops",
+    "Call Trace:",
+    "general protection fault",
+];
+
+/// Split a guest kernel message into its log level and its text.
+fn parse_kernel_level(line: &str) -> (Option<u8>, &str) {
+    let bytes = line.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'<' && bytes[1].is_ascii_digit() && bytes[2] == b'>' {
+        return (Some(bytes[1] - b'0'), &line[3..]);
+    }
+    if KERN_ERR_MARKERS.iter().any(|marker| line.contains(marker)) {
+        return (Some(KERN_ERR), line);
+    }
+    if line.contains("WARNING:") {
+        return (Some(KERN_WARNING), line);
+    }
+    (None, line)
+}
+
+/// Find the `subsystem` of a record.
+#[derive(Default)]
+struct SubsystemFinder(Option<String>);
+
+impl Serializer for SubsystemFinder {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        if key == "subsystem" {
+            self.0 = Some(val.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Route the guest dmesg, which Dragonball logs for the second serial port,
+/// to a plain-text file instead of the VMM log.
+///
+/// Guest kernel errors are also mirrored as warnings in the VMM log. The
+/// dmesg records bypass the log level of the VMM log.
+pub struct DmesgDrain<D> {
+    vmm_log: D,
+    dmesg_file: LogFile,
+}
+
+impl<D> DmesgDrain<D> {
+    pub fn new(vmm_log: D, dmesg_file: LogFile) -> Self {
+        DmesgDrain {
+            vmm_log,
+            dmesg_file,
+        }
+    }
+}
+
+impl<D: Drain<Ok = (), Err = slog::Never>> Drain for DmesgDrain<D> {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        let mut finder = SubsystemFinder::default();
+        let _ = values.serialize(record, &mut finder);
+        let _ = record.kv().serialize(record, &mut finder);
+        if finder.0.as_deref() != Some(DMESG_SUBSYSTEM) {
+            return self.vmm_log.log(record, values);
+        }
+
+        let message = record.msg().to_string();
+        for line in message.lines() {
+            let (level, text) = parse_kernel_level(line);
+            let _ = self
+                .dmesg_file
+                .clone()
+                .write_all(format!("{text}\n").as_bytes());
+
+            if level.is_some_and(|level| level <= KERN_ERR) {
+                let record_static = slog::record_static!(Level::Warning, "");
+                let warning = format!("guest kernel error: {text}");
+                self.vmm_log.log(
+                    &Record::new(&record_static, &format_args!("{warning}"), slog::b!()),
+                    values,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+
+const MAC_ADDR_LEN: usize = 6;
+
+/// Bit of the first octet marking a group (multicast) address.
+const MAC_MULTICAST_BIT: u8 = 0x01;
+/// Bit of the first octet marking a locally administered address.
+const MAC_LOCAL_BIT: u8 = 0x02;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A unicast MAC address that can be assigned to a guest network device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr([u8; MAC_ADDR_LEN]);
+
+impl MacAddr {
+    /// Derive a stable, locally administered MAC address from the VM ID and
+    /// the device ID, so that a device keeps its address across restarts.
+    pub fn generate(vm_id: &str, device_id: &str) -> Self {
+        let hash = fnv1a(vm_id.bytes().chain([b'/']).chain(device_id.bytes()));
+
+        let mut bytes = [0u8; MAC_ADDR_LEN];
+        bytes.copy_from_slice(&hash.to_be_bytes()[..MAC_ADDR_LEN]);
+        bytes[0] = (bytes[0] & !MAC_MULTICAST_BIT) | MAC_LOCAL_BIT;
+        MacAddr(bytes)
+    }
+}
+
+/// A stable hash of `bytes`. FNV-1a is used instead of `DefaultHasher`, whose
+/// output may change between Rust releases.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+impl FromStr for MacAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let octets: Vec<&str> = s.split(':').collect();
+        if octets.len() != MAC_ADDR_LEN {
+            return Err(anyhow!("invalid MAC address {s:?}"));
+        }
+
+        let mut bytes = [0u8; MAC_ADDR_LEN];
+        for (byte, octet) in bytes.iter_mut().zip(octets) {
+            if octet.len() != 2 {
+                return Err(anyhow!("invalid MAC address {s:?}"));
+            }
+            *byte =
+                u8::from_str_radix(octet, 16).map_err(|_| anyhow!("invalid MAC address {s:?}"))?;
+        }
+
+        if bytes[0] & MAC_MULTICAST_BIT != 0 {
+            return Err(anyhow!(
+                "MAC address {s} is a multicast address and cannot be used by a guest"
+            ));
+        }
+        if bytes == [0u8; MAC_ADDR_LEN] {
+            return Err(anyhow!("MAC address {s} is all zeros"));
+        }
+
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+/// Guest MAC addresses in use by the network devices of a VM, shared between
+/// the devices inserted at boot time and the ones hotplugged through the API.
+#[derive(Clone, Default)]
+pub struct GuestMacTable {
+    macs: Arc<Mutex<HashMap<MacAddr, String>>>,
+}
+
+impl GuestMacTable {
+    /// Record that `mac` is used by `device`.
+    pub fn insert(&self, mac: MacAddr, device: String) {
+        self.macs.lock().unwrap().insert(mac, device);
+    }
+
+    /// Forget all the MAC addresses, for a restarted VM.
+    pub fn clear(&self) {
+        self.macs.lock().unwrap().clear();
+    }
+
+    /// Fail if `mac` is already used by a network device.
+    pub fn check(&self, mac: &MacAddr) -> Result<()> {
+        match self.macs.lock().unwrap().get(mac) {
+            Some(device) => Err(anyhow!(
+                "guest MAC address {mac} is already used by {device}, set allow_duplicate_mac to share it"
+            )),
+            None => Ok(()),
+        }
+    }
+}
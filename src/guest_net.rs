@@ -0,0 +1,215 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use dragonball::api::v1::NetworkInterfaceConfig;
+use serde_json::{json, Value};
+
+use crate::guest_mac::{GuestMacTable, MacAddr};
+
+/// Version of the guest network config payload layout.
+const NET_CONFIG_VERSION: u32 = 1;
+/// The config disk is exposed to the guest as a virtio-blk device, whose size
+/// must be a multiple of the sector size.
+const SECTOR_SIZE: usize = 512;
+/// The kernel `ip=` parameter carries at most two DNS servers.
+const KERNEL_IP_MAX_DNS: usize = 2;
+
+/// A network device parsed from `--virnets` or the `insert_virnets` action.
+pub struct NetDevice {
+    pub config: NetworkInterfaceConfig,
+    pub guest_mac: MacAddr,
+    pub guest_net: Option<GuestNetConfig>,
+}
+
+/// Static IP configuration of a guest network device, given by the `ip`, `gw`
+/// and `dns` fields of a network device.
+pub struct GuestNetConfig {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Vec<IpAddr>,
+}
+
+impl GuestNetConfig {
+    /// Remove the `ip`, `gw` and `dns` fields from a network device, which are
+    /// not part of `NetworkInterfaceConfig`.
+    fn take_from(value: &mut Value) -> Result<Option<Self>> {
+        let Some(device) = value.as_object_mut() else {
+            return Ok(None);
+        };
+        let ip = device.remove("ip");
+        let gw = device.remove("gw");
+        let dns = device.remove("dns");
+
+        let ip = match ip {
+            Some(Value::String(ip)) => ip,
+            Some(_) => return Err(anyhow!("ip must be a string like 10.0.0.2/24")),
+            None if gw.is_some() || dns.is_some() => {
+                return Err(anyhow!("gw and dns can only be set together with ip"))
+            }
+            None => return Ok(None),
+        };
+        let (address, prefix_len) = ip
+            .split_once('/')
+            .ok_or_else(|| anyhow!("ip {ip} must be in the CIDR notation, e.g. 10.0.0.2/24"))?;
+        let address = Ipv4Addr::from_str(address).with_context(|| format!("Invalid ip {ip}"))?;
+        let prefix_len = u8::from_str(prefix_len)
+            .ok()
+            .filter(|len| *len <= 32)
+            .ok_or_else(|| anyhow!("Invalid prefix length of ip {ip}"))?;
+
+        let gateway = match gw {
+            Some(Value::String(gw)) => {
+                Some(Ipv4Addr::from_str(&gw).with_context(|| format!("Invalid gw {gw}"))?)
+            }
+            Some(_) => return Err(anyhow!("gw must be a string")),
+            None => None,
+        };
+
+        let dns = match dns {
+            Some(Value::Array(servers)) => servers
+                .iter()
+                .map(|server| {
+                    server
+                        .as_str()
+                        .and_then(|server| IpAddr::from_str(server).ok())
+                        .ok_or_else(|| anyhow!("Invalid dns server {server}"))
+                })
+                .collect::<Result<Vec<IpAddr>>>()?,
+            Some(_) => return Err(anyhow!("dns must be an array of IP addresses")),
+            None => vec![],
+        };
+
+        Ok(Some(GuestNetConfig {
+            address,
+            prefix_len,
+            gateway,
+            dns,
+        }))
+    }
+
+    fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - u32::from(self.prefix_len))
+                .unwrap_or(0),
+        )
+    }
+
+    /// The kernel `ip=` parameter configuring this device statically, in the
+    /// format `ip=<client>:<server>:<gw>:<netmask>:<hostname>:<device>:<autoconf>:<dns0>:<dns1>`.
+    pub fn kernel_ip_param(&self) -> String {
+        let gateway = self.gateway.map(|gw| gw.to_string()).unwrap_or_default();
+        let mut param = format!("ip={}::{}:{}:::off", self.address, gateway, self.netmask());
+        for dns in self
+            .dns
+            .iter()
+            .filter(|dns| dns.is_ipv4())
+            .take(KERNEL_IP_MAX_DNS)
+        {
+            param.push_str(&format!(":{dns}"));
+        }
+        param
+    }
+}
+
+/// Parse an array of `NetworkInterfaceConfig` in the format of JSON.
+///
+/// Devices without `guest_mac` get a MAC address generated from `vm_id` and
+/// the device ID. Multicast and malformed addresses are rejected, as well as
+/// addresses already in use by this VM unless `allow_duplicate_mac` is set.
+/// The guest MAC address should be recorded in `macs` once the device is
+/// inserted.
+pub fn parse_net_configs(
+    config_json: &str,
+    vm_id: &str,
+    macs: &GuestMacTable,
+) -> Result<Vec<NetDevice>> {
+    let values: Vec<Value> =
+        serde_json::from_str(config_json).context("Parse NetworkInterfaceConfig from JSON")?;
+
+    let mut devices: Vec<NetDevice> = Vec::with_capacity(values.len());
+    for mut value in values {
+        let backend = &value["backend"];
+        let device_id = backend["iface_id"]
+            .as_str()
+            .or_else(|| backend["sock_path"].as_str())
+            .ok_or_else(|| anyhow!("network device without iface_id or sock_path"))?
+            .to_string();
+        let allow_duplicate_mac = backend["allow_duplicate_mac"].as_bool().unwrap_or(false);
+
+        let guest_mac = match value["guest_mac"].as_str() {
+            Some(mac) => MacAddr::from_str(mac)
+                .with_context(|| format!("Invalid guest_mac of network device {device_id}"))?,
+            None if value["guest_mac"].is_null() => MacAddr::generate(vm_id, &device_id),
+            None => {
+                return Err(anyhow!(
+                    "guest_mac of network device {device_id} is not a string"
+                ))
+            }
+        };
+
+        if !allow_duplicate_mac {
+            macs.check(&guest_mac)?;
+            if devices.iter().any(|device| device.guest_mac == guest_mac) {
+                return Err(anyhow!(
+                    "guest MAC address {guest_mac} of network device {device_id} is used more than once"
+                ));
+            }
+        }
+
+        let guest_net = GuestNetConfig::take_from(&mut value)
+            .with_context(|| format!("Invalid guest network config of {device_id}"))?;
+        value["guest_mac"] = Value::String(guest_mac.to_string());
+        let config: NetworkInterfaceConfig = serde_json::from_value(value)
+            .with_context(|| format!("Parse NetworkInterfaceConfig of {device_id}"))?;
+        devices.push(NetDevice {
+            config,
+            guest_mac,
+            guest_net,
+        });
+    }
+
+    Ok(devices)
+}
+
+/// Write the network config of all the devices with a static IP configuration
+/// to `path`, so that a guest init can apply it.
+///
+/// The file holds a NUL padded JSON document and is attached to the guest as a
+/// read-only virtio-blk device. Returns false if there is nothing to write.
+pub fn write_config_disk(devices: &[NetDevice], path: &Path) -> Result<bool> {
+    let interfaces: Vec<Value> = devices
+        .iter()
+        .filter_map(|device| {
+            device.guest_net.as_ref().map(|net| {
+                json!({
+                    "mac": device.guest_mac.to_string(),
+                    "address": format!("{}/{}", net.address, net.prefix_len),
+                    "gateway": net.gateway.map(|gw| gw.to_string()),
+                    "dns": net.dns.iter().map(|dns| dns.to_string()).collect::<Vec<String>>(),
+                })
+            })
+        })
+        .collect();
+    if interfaces.is_empty() {
+        return Ok(false);
+    }
+
+    let mut payload = json!({
+        "version": NET_CONFIG_VERSION,
+        "interfaces": interfaces,
+    })
+    .to_string()
+    .into_bytes();
+    payload.resize(payload.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE, 0);
+
+    std::fs::write(path, payload)
+        .with_context(|| format!("Failed to write guest network config to {path:?}"))?;
+    Ok(true)
+}
@@ -0,0 +1,161 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use dragonball::api::v1::{InstanceInfo, VmmRequest, VmmResponse};
+use serde_json::json;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::parser::args::{GuestPanicArgs, OnPanic};
+use crate::restart;
+use crate::utils;
+use crate::vmm_comm_trait::VMMComm;
+
+/// The line the guest kernel prints on the console when it panics.
+pub const PANIC_BANNER: &[u8] = b"Kernel panic - not syncing";
+/// The number of console lines logged with a guest panic.
+const PANIC_CONSOLE_LINES: usize = 50;
+
+/// Set the guest `panic=` boot arg for the reaction to a panic: the guest
+/// waits in the panicked state for dbs-cli, unless it has to reboot.
+pub fn panic_boot_args(boot_args: &str, on_panic: Option<OnPanic>) -> String {
+    match on_panic {
+        None => boot_args.to_string(),
+        Some(OnPanic::Restart) => {
+            if utils::has_kernel_param(boot_args, "panic")
+                && !utils::has_kernel_param_value(boot_args, "panic=0")
+            {
+                boot_args.to_string()
+            } else {
+                utils::set_kernel_param(boot_args, "panic", "1")
+            }
+        }
+        Some(_) => {
+            if utils::has_kernel_param(boot_args, "panic")
+                && !utils::has_kernel_param_value(boot_args, "panic=0")
+            {
+                log::warn!("the panic= boot arg is replaced by panic=0 for --on-panic");
+            }
+            utils::set_kernel_param(boot_args, "panic", "0")
+        }
+    }
+}
+
+/// React to a guest kernel panic, which is detected by the panic banner on
+/// the guest console, as Dragonball has no pvpanic device.
+pub struct GuestPanicHandler {
+    pub vm_id: String,
+    pub vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+    pub to_vmm: Option<Sender<VmmRequest>>,
+    pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+    pub to_vmm_fd: EventFd,
+    pub args: GuestPanicArgs,
+    panicked: AtomicBool,
+}
+
+impl VMMComm for GuestPanicHandler {
+    fn get_to_vmm(&self) -> Option<&Sender<VmmRequest>> {
+        self.to_vmm.as_ref()
+    }
+
+    fn get_from_vmm(&self) -> Option<Arc<Mutex<Receiver<VmmResponse>>>> {
+        self.from_vmm.clone()
+    }
+
+    fn get_to_vmm_fd(&self) -> &EventFd {
+        &self.to_vmm_fd
+    }
+}
+
+impl GuestPanicHandler {
+    pub fn new(
+        vm_id: String,
+        vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+        to_vmm: Option<Sender<VmmRequest>>,
+        from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+        to_vmm_fd: EventFd,
+        args: GuestPanicArgs,
+    ) -> Self {
+        GuestPanicHandler {
+            vm_id,
+            vmm_shared_info,
+            to_vmm,
+            from_vmm,
+            to_vmm_fd,
+            args,
+            panicked: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the guest kernel has panicked.
+    pub fn panicked(&self) -> bool {
+        self.panicked.load(Ordering::SeqCst)
+    }
+
+    /// Watch for a panic again, for a restarted VM.
+    pub fn reset(&self) {
+        self.panicked.store(false, Ordering::SeqCst);
+    }
+
+    /// Handle a guest panic, given the panic banner line and the console
+    /// output kept so far. Only the first panic is handled.
+    pub fn handle(&self, banner: &str, console: &[u8]) {
+        if self.panicked.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let console = String::from_utf8_lossy(console);
+        let lines: Vec<&str> = console.lines().collect();
+        let last_lines = lines[lines.len().saturating_sub(PANIC_CONSOLE_LINES)..].join("\n");
+        slog::error!(slog_scope::logger(), "guest kernel panic";
+            "event" => "guest_panic",
+            "vm_id" => &self.vm_id,
+            "message" => banner.trim(),
+            "on_panic" => format!("{:?}", self.args.on_panic),
+            "console" => last_lines);
+
+        // a VM stopped on purpose is not restarted.
+        if matches!(
+            self.args.on_panic,
+            Some(OnPanic::Exit) | Some(OnPanic::Dump)
+        ) {
+            restart::request_stop();
+        }
+        let result = match self.args.on_panic {
+            None | Some(OnPanic::Pause) | Some(OnPanic::Restart) => Ok(()),
+            Some(OnPanic::Exit) => self.shutdown_microvm(),
+            Some(OnPanic::Dump) => self
+                .dump(banner, &console)
+                .and_then(|_| self.shutdown_microvm()),
+        };
+        if let Err(err) = result {
+            log::warn!("Failed to handle the guest kernel panic: {:?}", err);
+        }
+    }
+
+    /// Write the VM status and the whole console ring buffer to the dump file.
+    fn dump(&self, banner: &str, console: &str) -> Result<()> {
+        let state = self.vmm_shared_info.read().unwrap().state.clone();
+        let mut dump = json!({
+            "vm_id": self.vm_id,
+            "time": utils::utc_timestamp(),
+            "message": banner.trim(),
+            "state": format!("{state:?}"),
+            "console": console,
+        });
+        if let Ok(vm_config) = self.get_vm_configuration() {
+            dump["vcpu_count"] = json!(vm_config.vcpu_count);
+            dump["max_vcpu_count"] = json!(vm_config.max_vcpu_count);
+            dump["mem_size_mib"] = json!(vm_config.mem_size_mib);
+        }
+        let path = &self.args.panic_dump_file;
+        std::fs::write(path, serde_json::to_string_pretty(&dump)?)
+            .with_context(|| format!("Failed to write the panic dump file {path}"))?;
+        log::warn!("the guest panic dump is written to {}", path);
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{mount, MsFlags};
+use nix::sys::mman::{mlockall, munlockall, MlockAllFlags};
+use nix::sys::resource::{getrlimit, Resource};
+use nix::sys::statfs::{statfs, HUGETLBFS_MAGIC};
+
+use crate::numa;
+use crate::parser::args::{HugepageSize, MemArgs, MemPolicy};
+
+const HUGETLBFS: &str = "hugetlbfs";
+const HUGEPAGES_SYSFS_DIR: &str = "/sys/kernel/mm/hugepages";
+const NODE_SYSFS_DIR: &str = "/sys/devices/system/node";
+/// The directory of the hugetlbfs mounted by dbs-cli, one per page size,
+/// which are kept for the other VMs. It is apart from the runtime
+/// directories of the VMs in /run/dbs-cli, which are named after them.
+const HUGETLBFS_MOUNT_DIR: &str = "/run/dbs-cli/.hugetlbfs";
+/// The bit of CAP_IPC_LOCK in the capability sets, as in linux/capability.h.
+const CAP_IPC_LOCK: u32 = 14;
+
+impl HugepageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            HugepageSize::Size2M => 2 << 20,
+            HugepageSize::Size1G => 1 << 30,
+        }
+    }
+
+    /// The name of the size in sysfs and in the hugetlbfs mount options.
+    fn name(self) -> &'static str {
+        match self {
+            HugepageSize::Size2M => "2M",
+            HugepageSize::Size1G => "1G",
+        }
+    }
+
+    fn sysfs_dir(self) -> String {
+        format!("hugepages-{}kB", self.bytes() >> 10)
+    }
+}
+
+/// Whether the guest memory is backed by hugepages.
+pub fn is_hugetlbfs(args: &MemArgs) -> bool {
+    args.mem_type == HUGETLBFS
+}
+
+/// Check that the host has enough free hugepages of `--hugepage-size` for
+/// the guest memory, on the nodes of `--mem-host-nodes` if it is bound to
+/// them.
+pub fn check_free(args: &MemArgs) -> Result<()> {
+    if !is_hugetlbfs(args) {
+        return Ok(());
+    }
+    let size = args.hugepage_size;
+    let mem_bytes = args.mem_size as u64 * (1 << 20);
+    let needed = mem_bytes / size.bytes();
+    if needed * size.bytes() != mem_bytes {
+        return Err(anyhow!(
+            "--mem-size {} MiB is not a multiple of the {} hugepages",
+            args.mem_size,
+            size.name()
+        ));
+    }
+
+    let pool = Path::new(HUGEPAGES_SYSFS_DIR).join(size.sysfs_dir());
+    if !pool.exists() {
+        return Err(anyhow!(
+            "the host does not support {} hugepages",
+            size.name()
+        ));
+    }
+    // the reserved pages are promised to mappings of other processes.
+    let free = read_count(&pool.join("free_hugepages"))?
+        .saturating_sub(read_count(&pool.join("resv_hugepages"))?);
+    if free < needed {
+        return Err(anyhow!(
+            "{} MiB of guest memory needs {} free {} hugepages, the host has {}, short of {}; \
+             raise {}",
+            args.mem_size,
+            needed,
+            size.name(),
+            free,
+            needed - free,
+            pool.join("nr_hugepages").display()
+        ));
+    }
+
+    let nodes = match (&args.mem_host_nodes, args.mem_policy) {
+        (Some(nodes), None | Some(MemPolicy::Bind)) => numa::parse_cpu_list(nodes)?,
+        _ => return Ok(()),
+    };
+    let mut node_free = 0;
+    for node in nodes.iter() {
+        node_free += read_count(
+            &Path::new(NODE_SYSFS_DIR)
+                .join(format!("node{node}/hugepages"))
+                .join(size.sysfs_dir())
+                .join("free_hugepages"),
+        )?;
+    }
+    if node_free < needed {
+        return Err(anyhow!(
+            "{} MiB of guest memory needs {} free {} hugepages on the NUMA nodes {:?}, \
+             they have {}, short of {}",
+            args.mem_size,
+            needed,
+            size.name(),
+            nodes,
+            node_free,
+            needed - node_free
+        ));
+    }
+    Ok(())
+}
+
+fn read_count(path: &Path) -> Result<u64> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    content
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid content of {}", path.display()))
+}
+
+/// Make `--mem-file-path` a hugetlbfs of `--hugepage-size`: an existing one
+/// is checked, otherwise one is found in the mounts of the host or mounted.
+pub fn setup_mem_file(args: &mut MemArgs) -> Result<()> {
+    if !is_hugetlbfs(args) {
+        return Ok(());
+    }
+    let size = args.hugepage_size;
+    if !args.mem_file_path.is_empty() {
+        return check_hugetlbfs(&args.mem_file_path, size);
+    }
+    if let Some(path) = find_hugetlbfs(size)? {
+        args.mem_file_path = path;
+        return Ok(());
+    }
+
+    let path = format!("{HUGETLBFS_MOUNT_DIR}/{}", size.name());
+    std::fs::create_dir_all(&path).with_context(|| format!("Failed to create {path}"))?;
+    mount_hugetlbfs(Path::new(&path), size, None)?;
+    println!("Mounted a hugetlbfs of {} pages at {}", size.name(), path);
+    args.mem_file_path = path;
+    Ok(())
+}
+
+fn check_hugetlbfs(path: &str, size: HugepageSize) -> Result<()> {
+    let stat = statfs(path).with_context(|| format!("Failed to statfs {path}"))?;
+    if stat.filesystem_type() != HUGETLBFS_MAGIC {
+        return Err(anyhow!("--mem-file-path {path} is not a hugetlbfs"));
+    }
+    if stat.block_size() as u64 != size.bytes() {
+        return Err(anyhow!(
+            "--mem-file-path {path} is a hugetlbfs of {} KiB pages, not of {}",
+            stat.block_size() >> 10,
+            size.name()
+        ));
+    }
+    Ok(())
+}
+
+/// A hugetlbfs of `size` in the mounts of the host.
+fn find_hugetlbfs(size: HugepageSize) -> Result<Option<String>> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    Ok(mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.len() > 2 && fields[2] == HUGETLBFS).then(|| fields[1].to_string())
+        })
+        .find(|path| check_hugetlbfs(path, size).is_ok()))
+}
+
+/// Mount a hugetlbfs of `size` at `target`, owned by `owner` if given.
+pub fn mount_hugetlbfs(target: &Path, size: HugepageSize, owner: Option<(u32, u32)>) -> Result<()> {
+    let mut options = format!("pagesize={}", size.name());
+    if let Some((uid, gid)) = owner {
+        options.push_str(&format!(",uid={uid},gid={gid}"));
+    }
+    mount(
+        Some(HUGETLBFS),
+        target,
+        Some(HUGETLBFS),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(options.as_str()),
+    )
+    .with_context(|| format!("Failed to mount a hugetlbfs at {}", target.display()))
+}
+
+/// Lock the memory of dbs-cli, including the guest memory mapped later, with
+/// `--mem-prefault` or `--mem-lock`. The locking faults in the guest memory
+/// when Dragonball maps it.
+pub fn lock_memory(args: &MemArgs) -> Result<()> {
+    if !args.mem_prefault && !args.mem_lock {
+        return Ok(());
+    }
+    let (limit, _) = getrlimit(Resource::RLIMIT_MEMLOCK)
+        .context("Failed to get the RLIMIT_MEMLOCK of dbs-cli")?;
+    let mem_bytes = args.mem_size as u64 * (1 << 20);
+    // the guest memory is mapped later, where going over the limit fails.
+    if limit != libc::RLIM_INFINITY && limit < mem_bytes && !has_cap_ipc_lock()? {
+        return Err(anyhow!(
+            "RLIMIT_MEMLOCK of {} KiB is less than {} MiB of guest memory, raise it or grant CAP_IPC_LOCK to lock the guest memory",
+            limit >> 10,
+            args.mem_size
+        ));
+    }
+    mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE)
+        .context("Failed to lock the memory of dbs-cli")
+}
+
+/// Lock the memory again for a restarted VM with `--mem-prefault` only, as
+/// it was unlocked once the previous VM started.
+pub fn relock_prefaulted(args: &MemArgs) -> Result<()> {
+    if !args.mem_prefault || args.mem_lock {
+        return Ok(());
+    }
+    mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE)
+        .context("Failed to lock the memory of dbs-cli")
+}
+
+/// Whether dbs-cli has CAP_IPC_LOCK, which lifts the RLIMIT_MEMLOCK.
+fn has_cap_ipc_lock() -> Result<bool> {
+    let status = std::fs::read_to_string("/proc/self/status")
+        .context("Failed to read the capabilities of dbs-cli")?;
+    let cap_eff = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or_else(|| anyhow!("no effective capabilities in /proc/self/status"))?;
+    Ok(cap_eff & (1 << CAP_IPC_LOCK) != 0)
+}
+
+/// Unlock the guest memory once the VM is started with `--mem-prefault`
+/// only, it stays faulted in but may be swapped out.
+pub fn unlock_prefaulted(args: &MemArgs) {
+    if !args.mem_prefault || args.mem_lock {
+        return;
+    }
+    if let Err(err) = munlockall() {
+        log::warn!("Failed to unlock the prefaulted guest memory: {:?}", err);
+    }
+}
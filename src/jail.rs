@@ -0,0 +1,606 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::kill;
+use nix::sys::stat::{mknod, Mode, SFlag};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{
+    chdir, chown, chroot, fork, setgid, setgroups, setuid, ForkResult, Gid, Pid, Uid,
+};
+use serde_json::Value;
+
+use crate::cgroup::{self, Cgroup};
+use crate::digests;
+use crate::hugepages;
+use crate::netns;
+use crate::parser::args::{CreateArgs, HugepageSize, JailArgs, JailRoot};
+use crate::registry::{self, VmEntry};
+use crate::runtime_dir::CleanupGuard;
+use crate::signal_handler;
+
+/// The runtime directory of the VM in the jail.
+const JAIL_RUN_DIR: &str = "/run";
+const JAIL_STATE_DIR: &str = "/run/vms";
+const CREATE_ARGS_FILE: &str = "create-args.json";
+const JAIL_HUGEPAGES_DIR: &str = "/hugepages";
+const DEFAULT_API_SOCK_NAME: &str = "api.sock";
+
+const KVM_DEVICE: &str = "/dev/kvm";
+const NULL_DEVICE: &str = "/dev/null";
+const TUN_DEVICE: &str = "/dev/net/tun";
+const VFIO_DEVICE: &str = "/dev/vfio/vfio";
+
+/// The chroot of a VM, with the files the VMM needs.
+struct Jail {
+    root: PathBuf,
+    uid: u32,
+    gid: u32,
+    /// the files linked or created in the jail, removed on exit
+    files: Vec<PathBuf>,
+    /// the directories created in the jail, removed on exit if empty
+    dirs: Vec<PathBuf>,
+    /// the files bind-mounted in the jail, as (source, target)
+    mounts: Vec<(PathBuf, PathBuf)>,
+    /// whether the host sysfs is mounted read-only in the jail, for VFIO
+    sysfs: bool,
+    /// the page size of the hugetlbfs mounted in the jail for the guest memory
+    hugetlbfs: Option<HugepageSize>,
+}
+
+impl Jail {
+    fn new(root: PathBuf, uid: u32, gid: u32) -> Result<Self> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(&root)
+            .with_context(|| format!("Failed to create the jail {}", root.display()))?;
+        let mut jail = Jail {
+            root,
+            uid,
+            gid,
+            files: vec![],
+            dirs: vec![],
+            mounts: vec![],
+            sysfs: false,
+            hugetlbfs: None,
+        };
+        // the VMM writes its sockets, pid file and logs in the runtime directory.
+        let run_dir = jail.create_dir(JAIL_RUN_DIR)?;
+        std::fs::set_permissions(&run_dir, std::fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to chmod {}", run_dir.display()))?;
+        chown(&run_dir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+            .with_context(|| format!("Failed to chown {}", run_dir.display()))?;
+        jail.create_dir("/proc")?;
+        Ok(jail)
+    }
+
+    /// The host path of `path` in the jail.
+    fn host_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<PathBuf> {
+        let dir = self.host_path(path);
+        if !dir.exists() {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            self.dirs.push(dir.clone());
+        }
+        Ok(dir)
+    }
+
+    /// Make the host file `path` available in the root of the jail, and
+    /// return its path in the jail. The file stays owned by its host user, so
+    /// it has to be readable, and writable if `write`, by the jailed user.
+    fn add_file(&mut self, path: &str, write: bool) -> Result<String> {
+        self.check_access(path, write)?;
+        let source = Path::new(path);
+        let name = source
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid file path {path}"))?
+            .to_string_lossy()
+            .into_owned();
+        let target = self.root.join(&name);
+        if self.files.contains(&target) {
+            return Err(anyhow!("more than one file named {name} in the jail"));
+        }
+        // a file left by a previous run of the jail.
+        let _ = std::fs::remove_file(&target);
+
+        // a hard link is the same file, a bind mount does the same across
+        // file systems.
+        match std::fs::hard_link(source, &target) {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+                File::create(&target)
+                    .with_context(|| format!("Failed to create {}", target.display()))?;
+                self.mounts.push((source.to_path_buf(), target.clone()));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to link {path} into the jail"))
+            }
+        }
+        self.files.push(target);
+        Ok(format!("/{name}"))
+    }
+
+    /// Check the permissions of `path` for the jailed user, whose only group
+    /// is the jail gid, so that the VMM does not fail to open it in the jail.
+    fn check_access(&self, path: &str, write: bool) -> Result<()> {
+        if self.uid == 0 {
+            return Ok(());
+        }
+        let metadata = std::fs::metadata(path).with_context(|| format!("Failed to stat {path}"))?;
+        let mode = if metadata.uid() == self.uid {
+            metadata.mode() >> 6
+        } else if metadata.gid() == self.gid {
+            metadata.mode() >> 3
+        } else {
+            metadata.mode()
+        };
+        let needed = if write { 0o6 } else { 0o4 };
+        if mode & needed != needed {
+            return Err(anyhow!(
+                "{path} is not {} by uid {} and gid {} of the jail, chown or chmod it first",
+                if write {
+                    "readable and writable"
+                } else {
+                    "readable"
+                },
+                self.uid,
+                self.gid
+            ));
+        }
+        Ok(())
+    }
+
+    /// Create the host device node `path` at the same path in the jail,
+    /// owned by the jailed user rather than sharing the permissions of the
+    /// host node.
+    fn add_device(&mut self, path: &str) -> Result<()> {
+        let rdev = std::fs::metadata(path)
+            .with_context(|| format!("Failed to stat {path}"))?
+            .rdev();
+        let target = self.host_path(path);
+        if self.files.contains(&target) {
+            return Ok(());
+        }
+        if let Some(parent) = Path::new(path).parent() {
+            self.create_dir(&parent.to_string_lossy())?;
+        }
+        let _ = std::fs::remove_file(&target);
+        mknod(
+            &target,
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o600),
+            rdev,
+        )
+        .with_context(|| format!("Failed to create {}", target.display()))?;
+        self.files.push(target.clone());
+        chown(
+            &target,
+            Some(Uid::from_raw(self.uid)),
+            Some(Gid::from_raw(self.gid)),
+        )
+        .with_context(|| format!("Failed to chown {}", target.display()))?;
+        Ok(())
+    }
+
+    /// Mount the files, the host sysfs, the hugetlbfs and procfs of the jail,
+    /// in the mount namespace of the jail.
+    fn mount(&self) -> Result<()> {
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .context("Failed to make the mounts private")?;
+        for (source, target) in &self.mounts {
+            mount(
+                Some(source),
+                target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .with_context(|| format!("Failed to mount {} in the jail", source.display()))?;
+        }
+        if self.sysfs {
+            let sys = self.host_path("/sys");
+            mount(
+                Some("/sys"),
+                &sys,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .context("Failed to mount sysfs in the jail")?;
+            mount(
+                None::<&str>,
+                &sys,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+            .context("Failed to remount sysfs read-only in the jail")?;
+        }
+        if let Some(size) = self.hugetlbfs {
+            hugepages::mount_hugetlbfs(
+                &self.host_path(JAIL_HUGEPAGES_DIR),
+                size,
+                Some((self.uid, self.gid)),
+            )?;
+        }
+        mount(
+            Some("proc"),
+            &self.host_path("/proc"),
+            Some("proc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )
+        .context("Failed to mount procfs in the jail")?;
+        Ok(())
+    }
+
+    /// Remove the files and the empty directories created in the jail, the
+    /// logs of the VM are kept.
+    fn remove_files(&self) {
+        for file in &self.files {
+            let _ = std::fs::remove_file(file);
+        }
+        for dir in self.dirs.iter().rev() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+
+    /// Make the files of the VM available in the jail, and rewrite their
+    /// paths in `create_args` into paths in the jail.
+    fn add_vm_files(&mut self, create_args: &mut CreateArgs) -> Result<()> {
+        if !create_args.fs.is_empty() {
+            return Err(anyhow!("virtio-fs is not supported in the jail"));
+        }
+        for path in [&mut create_args.kernel_path, &mut create_args.initrd_path]
+            .into_iter()
+            .flatten()
+        {
+            *path = self.add_file(path, false)?;
+        }
+        if let Some(rootfs) = &mut create_args.rootfs_args.rootfs {
+            *rootfs = self.add_file(rootfs, !create_args.rootfs_args.is_read_only)?;
+        }
+        if !create_args.virblks.is_empty() {
+            create_args.virblks = self.add_json_files(&create_args.virblks, "path_on_host")?;
+        }
+        // the drives are in the root of the jail, as named by add_file.
+        for (path, _) in create_args.digest.drive_sha256.iter_mut() {
+            if let Some(name) = Path::new(path).file_name() {
+                *path = format!("/{}", name.to_string_lossy());
+            }
+        }
+        if !create_args.vhost_user_blks.is_empty() {
+            create_args.vhost_user_blks =
+                self.add_json_files(&create_args.vhost_user_blks, "sock_path")?;
+        }
+        if !create_args.vhost_user_fs.is_empty() {
+            create_args.vhost_user_fs =
+                self.add_json_files(&create_args.vhost_user_fs, "sock_path")?;
+        }
+
+        self.add_device(KVM_DEVICE)?;
+        self.add_device(NULL_DEVICE)?;
+        if !create_args.virnets.is_empty() {
+            self.add_device(TUN_DEVICE)?;
+        }
+        if let Some(sysfs_path) = &create_args.host_device.sysfs_path {
+            let iommu_group = std::fs::read_link(Path::new(sysfs_path).join("iommu_group"))
+                .with_context(|| format!("Failed to get the IOMMU group of {sysfs_path}"))?;
+            let iommu_group = iommu_group
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid IOMMU group of {sysfs_path}"))?
+                .to_string_lossy()
+                .into_owned();
+            self.add_device(VFIO_DEVICE)?;
+            self.add_device(&format!("/dev/vfio/{iommu_group}"))?;
+            self.create_dir("/sys")?;
+            self.sysfs = true;
+        }
+        // the guest memory is on a hugetlbfs of the jail, unless one of the
+        // host is given.
+        if hugepages::is_hugetlbfs(&create_args.mem) {
+            hugepages::check_free(&create_args.mem)?;
+            let dir = self.create_dir(JAIL_HUGEPAGES_DIR)?;
+            if create_args.mem.mem_file_path.is_empty() {
+                self.hugetlbfs = Some(create_args.mem.hugepage_size);
+            } else {
+                self.mounts
+                    .push((PathBuf::from(&create_args.mem.mem_file_path), dir));
+            }
+            create_args.mem.mem_file_path = JAIL_HUGEPAGES_DIR.to_string();
+        }
+        Ok(())
+    }
+
+    /// Add the files at `key` of a JSON device config or array of them. The
+    /// files are written to unless `is_read_only`, as the sockets are.
+    fn add_json_files(&mut self, configs: &str, key: &str) -> Result<String> {
+        let mut value: Value = serde_json::from_str(configs)
+            .with_context(|| format!("Invalid device config {configs}"))?;
+        let configs = match &mut value {
+            Value::Array(configs) => configs.iter_mut().collect(),
+            config => vec![config],
+        };
+        for config in configs {
+            let write = config["is_read_only"] != true;
+            if let Some(Value::String(path)) = config.get_mut(key) {
+                *path = self.add_file(path, write)?;
+            }
+        }
+        Ok(value.to_string())
+    }
+}
+
+/// The path of an output file of the VM in the jail, placed in its runtime
+/// directory if relative.
+fn jail_output_path(path: &str) -> String {
+    if Path::new(path).is_absolute() {
+        path.to_string()
+    } else {
+        format!("{JAIL_RUN_DIR}/{path}")
+    }
+}
+
+/// Create a VM in a jail, like the jailer of Firecracker, and wait for it:
+/// - a chroot in `<chroot-base-dir>/<name>/root`, with the files of the VM
+///   linked into it and the device nodes it needs
+/// - mount, PID and network namespaces of its own, or the network namespace
+///   of `--netns`
+/// - a cgroup v2 with the `--cgroup` settings
+/// - `dbs-cli create` running as `--uid` and `--gid`
+///
+/// The VM is registered with its name on the host, with the paths of its
+/// sockets there, so that it is managed as the VMs out of a jail.
+pub fn run_jail(
+    jail_args: JailArgs,
+    create_args: CreateArgs,
+    api_sock_path: &str,
+    state_dir: &str,
+) -> Result<i32> {
+    let name = create_args
+        .name
+        .clone()
+        .ok_or_else(|| anyhow!("dbs-cli jail requires --name"))?;
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(anyhow!("invalid VM name {name:?}"));
+    }
+    if create_args.daemon.daemonize {
+        return Err(anyhow!(
+            "--daemonize is not supported in the jail, run dbs-cli jail as a service instead"
+        ));
+    }
+    if create_args.cgroup.cgroup_path.is_some() {
+        return Err(anyhow!(
+            "--cgroup-path is not supported in the jail, use --cgroup of dbs-cli jail instead"
+        ));
+    }
+    if create_args.net_tc_redirect.is_some() {
+        return Err(anyhow!(
+            "--net-tc-redirect is not supported in the jail, create the tap device owned by --uid in --netns instead"
+        ));
+    }
+    // the network namespace of the jail has no tap devices otherwise.
+    if !create_args.virnets.is_empty() && create_args.netns.is_none() {
+        return Err(anyhow!(
+            "--virnets in the jail requires --netns, with the tap devices owned by --uid in it"
+        ));
+    }
+
+    let api_sock_path = if api_sock_path.is_empty() {
+        DEFAULT_API_SOCK_NAME
+    } else {
+        api_sock_path
+    };
+    let mut jail = Jail::new(
+        Path::new(&jail_args.chroot_base_dir)
+            .join(&name)
+            .join("root"),
+        jail_args.uid,
+        jail_args.gid,
+    )?;
+    // the registry entry is removed when dbs-cli jail returns.
+    let _cleanup = CleanupGuard;
+    let mut entry = VmEntry::new(
+        &name,
+        &name,
+        &jail
+            .host_path(&jail_output_path(api_sock_path))
+            .to_string_lossy(),
+        &create_args,
+    );
+    if create_args.serial_path != "stdio" {
+        entry.serial_path = jail
+            .host_path(&jail_output_path(&create_args.serial_path))
+            .to_string_lossy()
+            .into_owned();
+    }
+    registry::register(state_dir, &entry)?;
+
+    let result = prepare_and_run(&mut jail, &jail_args, create_args, api_sock_path);
+    jail.remove_files();
+    result
+}
+
+fn prepare_and_run(
+    jail: &mut Jail,
+    jail_args: &JailArgs,
+    mut create_args: CreateArgs,
+    api_sock_path: &str,
+) -> Result<i32> {
+    // the manifest lists the host paths of the files.
+    digests::resolve(&mut create_args)?;
+    jail.add_vm_files(&mut create_args)?;
+    let netns_path = create_args.netns.take();
+    create_args.runtime_dir = Some(JAIL_RUN_DIR.to_string());
+    create_args.jail = Some(JailRoot {
+        root: jail.root.to_string_lossy().into_owned(),
+        uid: jail.uid,
+        gid: jail.gid,
+    });
+    let args_path = jail.host_path(&format!("{JAIL_RUN_DIR}/{CREATE_ARGS_FILE}"));
+    std::fs::write(&args_path, serde_json::to_string(&create_args)?)
+        .with_context(|| format!("Failed to write {}", args_path.display()))?;
+    jail.files.push(args_path.clone());
+
+    let cgroup = if jail_args.cgroup.is_empty() {
+        None
+    } else {
+        let name = create_args.name.as_deref().unwrap_or_default();
+        let keys = jail_args.cgroup.iter().map(|(key, _)| key.as_str());
+        let cgroup = Cgroup::create(&jail_args.cgroup_parent, name, &cgroup::controllers(keys))?;
+        for (key, value) in &jail_args.cgroup {
+            if let Err(err) = cgroup.set(key, value) {
+                cgroup.remove();
+                return Err(err);
+            }
+        }
+        Some(cgroup)
+    };
+
+    let exe = std::env::current_exe().context("Failed to get the path of dbs-cli")?;
+    // the signals are forwarded to dbs-cli in the jail. No thread can be
+    // created once the PID namespace is unshared, so the forwarder gets the
+    // pid of the child later, and the signals stay pending until then.
+    signal_handler::block_signals()?;
+    let (child_sender, child_receiver) = crossbeam_channel::bounded(1);
+    thread::Builder::new()
+        .name("signal_forwarder".to_owned())
+        .spawn(move || {
+            if let Ok(child) = child_receiver.recv() {
+                forward_signals(child);
+            }
+        })
+        .context("Failed to spawn the signal forwarder")?;
+    unshare(CloneFlags::CLONE_NEWPID).context("Failed to create a PID namespace")?;
+
+    // SAFETY: the only other thread waits on the channel, holding no lock
+    // the child needs before it execs.
+    let child = match unsafe { fork() }.context("Failed to fork")? {
+        ForkResult::Child => {
+            let err = exec_in_jail(
+                jail,
+                cgroup.as_ref(),
+                netns_path.as_deref(),
+                &exe,
+                api_sock_path,
+                &args_path,
+            );
+            eprintln!("dbs-cli: failed to enter the jail: {err:?}");
+            std::process::exit(1);
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    let _ = child_sender.send(child);
+    let exit_code = wait_child(child);
+    if let Some(cgroup) = cgroup {
+        cgroup.remove();
+    }
+    exit_code
+}
+
+/// Enter the namespaces of the jail and exec `dbs-cli create`, which enters
+/// the chroot and drops the privileges. Returns on errors only.
+fn exec_in_jail(
+    jail: &Jail,
+    cgroup: Option<&Cgroup>,
+    netns_path: Option<&str>,
+    exe: &Path,
+    api_sock_path: &str,
+    args_path: &Path,
+) -> anyhow::Error {
+    let enter = || -> Result<()> {
+        if let Some(cgroup) = cgroup {
+            cgroup.add_process(0)?;
+        }
+        unshare(CloneFlags::CLONE_NEWNS).context("Failed to create a mount namespace")?;
+        match netns_path {
+            Some(netns_path) => netns::enter_netns(netns_path)?,
+            None => {
+                unshare(CloneFlags::CLONE_NEWNET).context("Failed to create a network namespace")?
+            }
+        }
+        jail.mount()
+    };
+    if let Err(err) = enter() {
+        return err;
+    }
+    let err = Command::new(exe)
+        .arg("--api-sock-path")
+        .arg(api_sock_path)
+        .arg("--state-dir")
+        .arg(JAIL_STATE_DIR)
+        .arg("create")
+        .arg("--create-args-json")
+        .arg(args_path)
+        .exec();
+    anyhow!(err).context("Failed to exec dbs-cli create")
+}
+
+fn forward_signals(child: Pid) {
+    let signals = signal_handler::handled_signals();
+    loop {
+        match signals.wait() {
+            Ok(signal) => {
+                let _ = kill(child, signal);
+            }
+            Err(err) => {
+                log::warn!("Failed to wait for signals: {:?}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Wait for dbs-cli in the jail, and return its exit code.
+fn wait_child(child: Pid) -> Result<i32> {
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, exit_code)) => return Ok(exit_code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(err).context("Failed to wait for dbs-cli in the jail"),
+        }
+    }
+}
+
+/// Read the create args written by `dbs-cli jail`.
+pub fn read_create_args(path: &str) -> Result<CreateArgs> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid create args in {path}"))
+}
+
+/// Enter the chroot of the jail and drop the privileges, before anything of
+/// the VM is created.
+pub fn enter(jail: &JailRoot) -> Result<()> {
+    chroot(jail.root.as_str()).with_context(|| format!("Failed to chroot to {}", jail.root))?;
+    chdir("/").context("Failed to chdir to the jail")?;
+    let gid = Gid::from_raw(jail.gid);
+    setgroups(&[gid]).context("Failed to drop the supplementary groups")?;
+    setgid(gid).with_context(|| format!("Failed to set the gid to {}", jail.gid))?;
+    setuid(Uid::from_raw(jail.uid))
+        .with_context(|| format!("Failed to set the uid to {}", jail.uid))?;
+    Ok(())
+}
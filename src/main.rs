@@ -3,28 +3,121 @@
 extern crate slog_term;
 
 use anyhow::Result;
-use api_client::run_api_client;
+use api_client::{run_api_client, run_get_client, run_shutdown_client};
+use check_host::run_check_host;
 use clap::Parser;
+use console_client::run_console_client;
+use netd::run_netd;
 use parser::run_with_cli;
 
 use crate::parser::args::{Commands, DBSArgs};
 
+mod affinity;
 mod api_client;
 mod api_server;
+mod cgroup;
+mod check_host;
+mod clearcpuid;
 mod cli_instance;
+mod console_client;
+mod console_log;
+mod console_server;
+mod cpu_topology;
+mod daemon;
+mod digests;
+mod dmesg;
+mod guest_mac;
+mod guest_net;
+mod guest_panic;
+mod hugepages;
+mod jail;
+mod netd;
+mod netns;
+mod numa;
 mod parser;
+mod registry;
+mod restart;
+mod runtime_dir;
+mod seccomp;
+mod signal_handler;
+mod stdio_console;
 mod utils;
+mod vhost_user;
 mod vmm_comm_trait;
 
 fn main() -> Result<()> {
     let args: DBSArgs = DBSArgs::parse();
     match args.command {
-        Some(Commands::Create { create_args }) => {
-            utils::setup_db_log(&create_args.log_file, &create_args.log_level);
-            run_with_cli(create_args, &args.api_sock_path)?;
+        Some(Commands::Create {
+            create_args_json,
+            mut create_args,
+        }) => {
+            if let Some(create_args_json) = create_args_json {
+                create_args = jail::read_create_args(&create_args_json)?;
+            }
+            if let Some(jail) = &create_args.jail {
+                jail::enter(jail)?;
+            }
+            let api_sock_path = runtime_dir::setup(&mut create_args, &args.api_sock_path)?;
+            utils::setup_db_log(
+                &create_args.log_file,
+                &create_args.log_level,
+                &create_args.dmesg_file,
+            );
+            run_with_cli(create_args, &api_sock_path, &args.state_dir)?;
+        }
+        Some(Commands::Jail {
+            jail_args,
+            create_args,
+        }) => {
+            utils::setup_db_log(&create_args.log_file, &create_args.log_level, &None);
+            let exit_code =
+                jail::run_jail(jail_args, create_args, &args.api_sock_path, &args.state_dir)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
         }
         Some(Commands::Update { update_args }) => {
-            run_api_client(update_args, &args.api_sock_path)?;
+            let api_sock_path = registry::resolve_api_sock_path(
+                &args.state_dir,
+                &update_args.name,
+                &args.api_sock_path,
+            )?;
+            run_api_client(update_args, &api_sock_path)?;
+        }
+        Some(Commands::Get { get_args }) => {
+            let api_sock_path = registry::resolve_api_sock_path(
+                &args.state_dir,
+                &get_args.name,
+                &args.api_sock_path,
+            )?;
+            run_get_client(get_args, &api_sock_path)?;
+        }
+        Some(Commands::Console { mut console_args }) => {
+            if let Some(name) = &console_args.name {
+                console_args.serial_path = registry::lookup(&args.state_dir, name)?.serial_path;
+            }
+            run_console_client(console_args)?;
+        }
+        Some(Commands::Shutdown { shutdown_args }) => {
+            let api_sock_path = registry::resolve_api_sock_path(
+                &args.state_dir,
+                &shutdown_args.name,
+                &args.api_sock_path,
+            )?;
+            run_shutdown_client(&api_sock_path)?;
+        }
+        Some(Commands::List) => {
+            registry::run_list(&args.state_dir)?;
+        }
+        Some(Commands::Netd { netd_args }) => {
+            run_netd(netd_args)?;
+        }
+        Some(Commands::CheckHost { check_host_args }) => {
+            let exit_code = run_check_host(check_host_args)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
         }
         _ => {
             panic!("Invalid command provided for dbs-cli.");
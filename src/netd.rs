@@ -0,0 +1,271 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+
+use crate::parser::args::NetdArgs;
+
+/// A vhost-user message carries at most 8 file descriptors.
+const VHOST_USER_MAX_FDS: usize = 8;
+const RELAY_BUF_SIZE: usize = 64 * 1024;
+/// The data queued for a side that does not keep up, beyond which the other
+/// side is not read from until it catches up.
+const RELAY_MAX_QUEUED: usize = 1 << 20;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Launch passt as the user-mode networking backend of a vhost-user-net
+/// device.
+///
+/// passt does the NAT and the port forwarding on the host's sockets as an
+/// unprivileged vhost-user backend. As the Dragonball vhost-user net device is
+/// the server side of its socket, as passt is, netd connects to both and
+/// relays the vhost-user messages, along with their file descriptors, in
+/// between. Each connection of the VM gets a fresh passt, so that the network
+/// comes back when the guest reboots or the VM is restarted.
+pub fn run_netd(args: NetdArgs) -> Result<()> {
+    println!(
+        "dbs-cli: netd is waiting for the VM on {}. Insert a vhost-user-net device with this sock_path.",
+        args.sock_path
+    );
+    loop {
+        // the VM side has no timeout, since the device may be hotplugged any time.
+        let vm_stream = connect(&args.sock_path, Duration::MAX)?;
+        serve(&args, &vm_stream)?;
+        println!(
+            "dbs-cli: the VM disconnected from {}, waiting for it to connect again",
+            args.sock_path
+        );
+        // the socket of the VM is the one of a stopped VM until it is created
+        // again.
+        thread::sleep(CONNECT_RETRY_INTERVAL);
+    }
+}
+
+/// Relay a connection of the VM to a fresh passt until either side closes it.
+fn serve(args: &NetdArgs, vm_stream: &UnixStream) -> Result<()> {
+    let backend_sock_path = format!("{}.passt", args.sock_path);
+    let backend_sock = Path::new(&backend_sock_path);
+    if backend_sock.exists() {
+        std::fs::remove_file(backend_sock)?;
+    }
+
+    let mut passt = Command::new(&args.passt_path);
+    passt
+        .args(["--vhost-user", "--foreground", "--quiet", "--socket"])
+        .arg(&backend_sock_path);
+    for port in args.tcp_forward.iter() {
+        passt.args(["--tcp-ports", port]);
+    }
+    for port in args.udp_forward.iter() {
+        passt.args(["--udp-ports", port]);
+    }
+    // passt goes away with netd, even when netd is killed.
+    // SAFETY: prctl is async-signal-safe, as pre_exec requires.
+    unsafe {
+        passt.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let backend = Backend(
+        passt
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", args.passt_path))?,
+    );
+
+    let timeout = Duration::from_secs(args.connect_timeout);
+    let result = connect(&backend_sock_path, timeout)
+        .and_then(|backend_stream| relay(vm_stream, &backend_stream));
+    drop(backend);
+    let _ = std::fs::remove_file(backend_sock);
+    result
+}
+
+/// The user-mode networking backend, killed on drop.
+struct Backend(Child);
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn connect(sock_path: &str, timeout: Duration) -> Result<UnixStream> {
+    let start = Instant::now();
+    loop {
+        match UnixStream::connect(sock_path) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if start.elapsed() >= timeout => {
+                return Err(err).with_context(|| format!("Failed to connect to {sock_path}"))
+            }
+            Err(_) => thread::sleep(CONNECT_RETRY_INTERVAL),
+        }
+    }
+}
+
+/// A chunk of data received from one side, with the file descriptors which
+/// go with its first byte.
+struct Chunk {
+    data: Vec<u8>,
+    sent: usize,
+    fds: Vec<RawFd>,
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // the peer holds its own copies once they are sent.
+        for fd in self.fds.drain(..) {
+            let _ = nix::unistd::close(fd);
+        }
+    }
+}
+
+/// The data received from `from`, waiting to be sent to `to`.
+struct Direction {
+    from: RawFd,
+    to: RawFd,
+    queue: VecDeque<Chunk>,
+    queued: usize,
+}
+
+impl Direction {
+    fn new(from: RawFd, to: RawFd) -> Self {
+        Direction {
+            from,
+            to,
+            queue: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    /// Receive what `from` has. Returns false on EOF.
+    fn receive(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut cmsg_buf = cmsg_space!([RawFd; VHOST_USER_MAX_FDS]);
+        let mut iov = [IoSliceMut::new(buf)];
+        let msg = match recvmsg::<()>(
+            self.from,
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC | MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(msg) => msg,
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(true),
+            Err(Errno::ECONNRESET) => return Ok(false),
+            Err(err) => return Err(anyhow!("Failed to receive a vhost-user message: {err}")),
+        };
+
+        let mut fds: Vec<RawFd> = Vec::new();
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        let len = msg.bytes;
+        let chunk = Chunk {
+            data: buf[..len].to_vec(),
+            sent: 0,
+            fds,
+        };
+        if len == 0 {
+            return Ok(false);
+        }
+        self.queued += len;
+        self.queue.push_back(chunk);
+        Ok(true)
+    }
+
+    /// Send the queued data to `to` until it would block. Returns false if
+    /// `to` is closed.
+    fn send(&mut self) -> Result<bool> {
+        while let Some(chunk) = self.queue.front_mut() {
+            let iov = [IoSlice::new(&chunk.data[chunk.sent..])];
+            let cmsgs = [ControlMessage::ScmRights(&chunk.fds)];
+            // the file descriptors go with the first byte only.
+            let cmsgs: &[ControlMessage] = if chunk.sent == 0 && !chunk.fds.is_empty() {
+                &cmsgs
+            } else {
+                &[]
+            };
+            let flags = MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL;
+            let sent = match sendmsg::<()>(self.to, &iov, cmsgs, flags, None) {
+                Ok(sent) => sent,
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(true),
+                Err(Errno::EPIPE) | Err(Errno::ECONNRESET) => return Ok(false),
+                Err(err) => return Err(anyhow!("Failed to send a vhost-user message: {err}")),
+            };
+            chunk.sent += sent;
+            self.queued -= sent;
+            if chunk.sent == chunk.data.len() {
+                self.queue.pop_front();
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Relay the data between the two streams until either of them is closed.
+///
+/// Both directions are served by a single poll loop without blocking, with
+/// the data a side does not take yet queued, so that a side which stops
+/// reading holds up neither the other direction nor the loop.
+fn relay(a: &UnixStream, b: &UnixStream) -> Result<()> {
+    let (a, b) = (a.as_raw_fd(), b.as_raw_fd());
+    let mut directions = [Direction::new(a, b), Direction::new(b, a)];
+    let mut buf = vec![0u8; RELAY_BUF_SIZE];
+    loop {
+        // fds[i] is the `from` side of directions[i], and the `to` side of
+        // the other direction.
+        let mut fds: Vec<PollFd> = (0..2)
+            .map(|i| {
+                let mut events = PollFlags::empty();
+                if directions[i].queued < RELAY_MAX_QUEUED {
+                    events |= PollFlags::POLLIN;
+                }
+                if !directions[1 - i].queue.is_empty() {
+                    events |= PollFlags::POLLOUT;
+                }
+                PollFd::new(directions[i].from, events)
+            })
+            .collect();
+        match poll(&mut fds, -1) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(anyhow!("Failed to poll the vhost-user sockets: {err}")),
+        }
+        let ready: Vec<PollFlags> = fds
+            .iter()
+            .map(|fd| fd.revents().unwrap_or_else(PollFlags::empty))
+            .collect();
+
+        for (direction, ready) in directions.iter_mut().zip(ready) {
+            if ready.contains(PollFlags::POLLIN) {
+                if !direction.receive(&mut buf)? {
+                    return Ok(());
+                }
+            } else if ready.intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+                return Ok(());
+            }
+        }
+        for direction in directions.iter_mut() {
+            if !direction.send()? {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use nix::sched::{setns, CloneFlags};
+
+use crate::runtime_dir;
+
+/// Move the calling thread into the network namespace at `netns_path`.
+///
+/// Threads created afterwards inherit the namespace, so this must be called
+/// before the VMM and its helper threads are spawned.
+pub fn enter_netns(netns_path: &str) -> Result<()> {
+    let netns = File::open(netns_path)
+        .with_context(|| format!("Failed to open network namespace {netns_path}"))?;
+    setns(netns.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+        .with_context(|| format!("Failed to enter network namespace {netns_path}"))?;
+    Ok(())
+}
+
+/// A tap device wired to a veth with tc mirred redirect in both directions,
+/// the way Kata Containers plumbs a pod network into the guest.
+///
+/// The tap device and the ingress qdiscs are removed on exit.
+#[derive(Clone)]
+pub struct TcRedirect {
+    tap: String,
+    veth: String,
+}
+
+impl TcRedirect {
+    pub fn setup(tap: &str, veth: &str, multi_queue: bool) -> Result<()> {
+        let mut tuntap_args = vec!["tuntap", "add", "dev", tap, "mode", "tap"];
+        if multi_queue {
+            tuntap_args.push("multi_queue");
+        }
+        run_cmd("ip", &tuntap_args)?;
+
+        // From here on the exit hook takes care of a partial setup.
+        let redirect = TcRedirect {
+            tap: tap.to_string(),
+            veth: veth.to_string(),
+        };
+        let hook = redirect.clone();
+        runtime_dir::on_exit(move || hook.remove());
+        run_cmd("ip", &["link", "set", "dev", tap, "up"])?;
+        redirect.redirect_ingress(veth, tap)?;
+        redirect.redirect_ingress(tap, veth)
+    }
+
+    /// Redirect all the packets received on `from` to the egress of `to`.
+    fn redirect_ingress(&self, from: &str, to: &str) -> Result<()> {
+        run_cmd("tc", &["qdisc", "add", "dev", from, "ingress"])?;
+        run_cmd(
+            "tc",
+            &[
+                "filter", "add", "dev", from, "parent", "ffff:", "protocol", "all", "u32", "match",
+                "u8", "0", "0", "action", "mirred", "egress", "redirect", "dev", to,
+            ],
+        )
+    }
+
+    fn remove(&self) {
+        // best effort: the veth belongs to the caller and stays in place.
+        let _ = run_cmd("tc", &["qdisc", "del", "dev", &self.veth, "ingress"]);
+        let _ = run_cmd("ip", &["link", "del", "dev", &self.tap]);
+    }
+}
+
+fn run_cmd(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to execute {program}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`{} {}` failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
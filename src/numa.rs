@@ -0,0 +1,120 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::parser::args::{MemArgs, MemPolicy};
+
+const NODE_SYSFS_DIR: &str = "/sys/devices/system/node";
+
+/// The modes of set_mempolicy(2).
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_INTERLEAVE: libc::c_int = 3;
+
+/// Parse a CPU or node list as in sysfs, e.g. `0-3,8-11`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first
+            .parse()
+            .map_err(|_| anyhow!("invalid CPU list {list:?}"))?;
+        let last: usize = last
+            .parse()
+            .map_err(|_| anyhow!("invalid CPU list {list:?}"))?;
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// The NUMA nodes of the host with their CPUs, ordered by node ID. A host
+/// without NUMA support is a single node 0 of all the online CPUs.
+pub fn host_nodes() -> Result<Vec<(u32, Vec<usize>)>> {
+    let dir = match std::fs::read_dir(NODE_SYSFS_DIR) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let online = std::fs::read_to_string("/sys/devices/system/cpu/online")
+                .context("Failed to read the online CPUs")?;
+            return Ok(vec![(0, parse_cpu_list(&online)?)]);
+        }
+        Err(err) => return Err(err).context("Failed to read the NUMA nodes"),
+    };
+    let mut nodes = vec![];
+    for entry in dir {
+        let entry = entry?;
+        let node = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        {
+            Some(node) => node,
+            None => continue,
+        };
+        let cpulist = std::fs::read_to_string(entry.path().join("cpulist"))
+            .with_context(|| format!("Failed to read the CPUs of NUMA node {node}"))?;
+        nodes.push((node, parse_cpu_list(&cpulist)?));
+    }
+    nodes.sort_unstable();
+    Ok(nodes)
+}
+
+/// Place the memory of dbs-cli, which is mostly the guest memory, on the
+/// NUMA nodes of `--mem-host-nodes`.
+///
+/// Dragonball does not expose the guest memory regions to mbind(2) them, so
+/// the policy is set with set_mempolicy(2) instead. That policy is per
+/// thread, and inherited by the threads spawned afterwards, so setting it
+/// before any thread is spawned makes it process-wide: it covers every
+/// allocation of dbs-cli, such as the heap of the VMM and the buffers of
+/// the devices, not only the guest memory.
+pub fn set_mem_policy(args: &MemArgs) -> Result<()> {
+    let (nodes, policy) = match (&args.mem_host_nodes, args.mem_policy) {
+        (Some(nodes), policy) => (parse_cpu_list(nodes)?, policy.unwrap_or(MemPolicy::Bind)),
+        (None, Some(_)) => return Err(anyhow!("--mem-policy requires --mem-host-nodes")),
+        (None, None) => return Ok(()),
+    };
+    let host_nodes = host_nodes()?;
+    for node in nodes.iter() {
+        if !host_nodes.iter().any(|(id, _)| *id as usize == *node) {
+            return Err(anyhow!("NUMA node {node} does not exist on the host"));
+        }
+    }
+    let mode = match policy {
+        MemPolicy::Bind => MPOL_BIND,
+        MemPolicy::Preferred if nodes.len() > 1 => {
+            return Err(anyhow!(
+                "--mem-policy preferred takes a single node in --mem-host-nodes"
+            ))
+        }
+        MemPolicy::Preferred => MPOL_PREFERRED,
+        MemPolicy::Interleave => MPOL_INTERLEAVE,
+    };
+
+    let bits = libc::c_ulong::BITS as usize;
+    let max_node = nodes.iter().max().copied().unwrap_or(0) + 1;
+    let mut node_mask = vec![0 as libc::c_ulong; max_node.div_ceil(bits)];
+    for node in nodes.iter() {
+        node_mask[node / bits] |= 1 << (node % bits);
+    }
+    // SAFETY: the node mask has room for max_node bits.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            mode,
+            node_mask.as_ptr(),
+            // the kernel ignores the last bit of maxnode.
+            max_node + 1,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| {
+            format!(
+                "Failed to set the {:?} memory policy on the NUMA nodes {:?}",
+                policy, nodes
+            )
+        });
+    }
+    Ok(())
+}
@@ -2,7 +2,7 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use serde_derive::{Deserialize, Serialize};
 
 /// A simple command-line tool to start DragonBall micro-VM
@@ -20,6 +20,15 @@ pub struct DBSArgs {
         display_order = 2
     )]
     pub api_sock_path: String,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "/run/dbs-cli/vms",
+        help = "The directory the VMs created with --name are registered in",
+        display_order = 2
+    )]
+    pub state_dir: String,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -27,15 +36,52 @@ pub struct DBSArgs {
 pub enum Commands {
     /// Create Dragonball Instance
     Create {
+        /// the create args written by `dbs-cli jail`, instead of the command line
+        #[clap(long, value_parser, hide = true)]
+        create_args_json: Option<String>,
         // create args are for setting up Dragonball CPU/
         #[clap(flatten)]
         create_args: CreateArgs,
     },
+    /// Create a Dragonball Instance in a chroot, in namespaces of its own and as an unprivileged user
+    Jail {
+        #[clap(flatten)]
+        jail_args: JailArgs,
+        #[clap(flatten)]
+        create_args: CreateArgs,
+    },
     /// Connect to Dragonball Api Server and update the Dragonball VM (Must create a api socket when creating the Dragonball VM)
     Update {
         #[clap(flatten)]
         update_args: UpdateArgs,
     },
+    /// Connect to Dragonball Api Server and get information about the Dragonball VM
+    Get {
+        #[clap(flatten)]
+        get_args: GetArgs,
+    },
+    /// Attach the terminal to the serial console of a Dragonball VM created with --serial-path
+    Console {
+        #[clap(flatten)]
+        console_args: ConsoleArgs,
+    },
+    /// Launch passt as the user-mode networking backend of a vhost-user-net device, which needs no privileges
+    Netd {
+        #[clap(flatten)]
+        netd_args: NetdArgs,
+    },
+    /// Stop a Dragonball VM through its Api Server
+    Shutdown {
+        #[clap(flatten)]
+        shutdown_args: ShutdownArgs,
+    },
+    /// List the running Dragonball VMs created with --name
+    List,
+    /// Check whether the host is ready to run Dragonball VMs
+    CheckHost {
+        #[clap(flatten)]
+        check_host_args: CheckHostArgs,
+    },
 }
 
 /// CPU topology related configurations
@@ -124,6 +170,34 @@ pub struct CreateArgs {
     #[clap(flatten)]
     pub host_device: HostDeviceArgs,
 
+    /// capture of the guest console output
+    #[clap(flatten)]
+    pub console_log: ConsoleLogArgs,
+
+    /// reaction to a guest kernel panic
+    #[clap(flatten)]
+    pub guest_panic: GuestPanicArgs,
+
+    /// restart of the VM when it stops
+    #[clap(flatten)]
+    pub restart: RestartArgs,
+
+    /// running as a service
+    #[clap(flatten)]
+    pub daemon: DaemonArgs,
+
+    /// seccomp filters of the VMM and vCPU threads
+    #[clap(flatten)]
+    pub seccomp: SeccompArgs,
+
+    /// cgroup v2 resource control
+    #[clap(flatten)]
+    pub cgroup: CgroupArgs,
+
+    /// SHA-256 digests the boot artifacts are verified against
+    #[clap(flatten)]
+    pub digest: DigestArgs,
+
     // The serial path used to communicate with VM
     #[clap(
         short,
@@ -135,6 +209,30 @@ pub struct CreateArgs {
     )]
     pub serial_path: String,
 
+    #[clap(
+        long,
+        value_parser,
+        help = "The directory for the sockets, the pid file and the generated images of the VM given by relative paths, which are removed on exit, /run/dbs-cli/<name> by default. The logs given by relative paths are kept there with an explicit --runtime-dir",
+        display_order = 2
+    )]
+    pub runtime_dir: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The name to register the VM with, so that update, get, console and shutdown find it by --name",
+        display_order = 2
+    )]
+    pub name: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The ID of the VM, which seeds the generated guest MAC addresses, the name by default or one derived from the kernel and the rootfs",
+        display_order = 2
+    )]
+    pub vm_id: Option<String>,
+
     // The path to a vsock socket file
     // FIXME: add more params:
     // cid="contextid",socket_path="somepath",gid="guest_id"
@@ -152,7 +250,9 @@ pub struct CreateArgs {
         long,
         value_parser,
         default_value = "",
-        help = r#"Insert virtio devices into the Dragonball before launched. The supported devices are virtio-net and vhost-net."#,
+        help = r#"Insert virtio devices into the Dragonball before launched. The supported devices are virtio-net and vhost-net.
+A stable guest_mac is generated from the VM ID and iface_id when it is omitted.
+A static guest network config can be given with "ip":"10.0.0.2/24","gw":"10.0.0.1","dns":["10.0.0.1"]."#,
         display_order = 2
     )]
     pub virnets: String,
@@ -177,6 +277,53 @@ The type of it is an array of BlockDeviceConfigInfo, e.g.
     )]
     pub fs: String,
 
+    #[clap(
+        long,
+        value_parser,
+        default_value = "",
+        help = r#"Insert vhost-user-blk devices backed by external targets (e.g. SPDK) into the Dragonball.
+The type of it is an array of VhostUserBlkConfig, e.g.
+    --vhost-user-blks '[{"drive_id":"vub0","sock_path":"/tmp/vhost-blk.sock","num_queues":1,"queue_size":128}]'"#,
+        display_order = 2
+    )]
+    pub vhost_user_blks: String,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "",
+        help = r#"Insert a vhost-user-fs device backed by an external daemon (e.g. virtiofsd) into the Dragonball using the VhostUserFsConfig, e.g.
+    --vhost-user-fs '{"tag":"myfs","sock_path":"/tmp/virtiofsd.sock","num_queues":1,"queue_size":1024}'"#,
+        display_order = 2
+    )]
+    pub vhost_user_fs: String,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The path of a network namespace (e.g. /var/run/netns/<name>) to run the VMM in",
+        display_order = 2
+    )]
+    pub netns: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = r#"Create the tap device of the first virtio-net/vhost-net device in --virnets and redirect traffic between it and the given veth with tc mirred, e.g.
+    --net-tc-redirect veth0"#,
+        display_order = 2
+    )]
+    pub net_tc_redirect: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "dbs-cli-netcfg.img",
+        help = "The path of the disk image holding the guest network config of --virnets, attached to the guest when any device has an ip, relative to the runtime directory",
+        display_order = 2
+    )]
+    pub net_config_disk: String,
+
     // feature for log
     #[clap(long, value_parser, default_value = "dbs-cli.log", display_order = 1)]
     pub log_file: String,
@@ -184,6 +331,14 @@ The type of it is an array of BlockDeviceConfigInfo, e.g.
     #[clap(long, value_parser, default_value = "Debug", display_order = 1)]
     pub log_level: String,
 
+    #[clap(
+        long,
+        value_parser,
+        help = "The file the guest dmesg on the second serial port (ttyS1) is written to, instead of the log file",
+        display_order = 1
+    )]
+    pub dmesg_file: Option<String>,
+
     #[clap(
         short,
         long,
@@ -216,6 +371,11 @@ The type of it is an array of BlockDeviceConfigInfo, e.g.
     /// rootfs
     #[clap(flatten)]
     pub rootfs_args: RootfsArgs,
+
+    /// the jail to enter, set by `dbs-cli jail` only
+    #[clap(skip)]
+    #[serde(default)]
+    pub jail: Option<JailRoot>,
 }
 
 #[derive(Args, Debug, Serialize, Deserialize, Clone)]
@@ -253,10 +413,117 @@ pub struct CpuArgs {
         display_order = 1
     )]
     pub vpmu_feature: u8,
+    #[clap(
+        long,
+        value_parser = parse_vcpu_pinning,
+        help = "Pin vCPUs to host CPUs as <vcpu>:<host cpu>, e.g. 0:2,1:3",
+        display_order = 1
+    )]
+    pub vcpu_affinity: Option<VcpuPinning>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Pin the vCPUs not in --vcpu-affinity to the host CPUs dbs-cli may run on, by NUMA node [default: no pinning]",
+        display_order = 1
+    )]
+    pub affinity_policy: Option<AffinityPolicy>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "host",
+        help = "Hide the features of the host above an x86-64 level from the guest kernel with clearcpuid=, host to hide none. Guest applications still see them with CPUID",
+        display_order = 1
+    )]
+    pub guest_clearcpuid_level: ClearCpuidLevel,
+    #[clap(
+        long,
+        value_parser = parse_clearcpuid_features,
+        allow_hyphen_values = true,
+        help = "CPU features to keep (+) or hide (-) from the guest kernel with clearcpuid=, besides --guest-clearcpuid-level, e.g. +aes,-tsx",
+        display_order = 1
+    )]
+    pub guest_clearcpuid: Option<ClearCpuidFeatures>,
     #[clap(flatten)]
     pub cpu_topology: CpuTopologyArgs,
 }
 
+/// The features the guest kernel keeps, the x86-64 microarchitecture levels
+/// as the baselines of fleets of mixed CPU generations.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ClearCpuidLevel {
+    /// All the features of the host
+    Host,
+    /// SSE4.2, SSSE3, POPCNT and CMPXCHG16B
+    #[value(name = "x86-64-v2")]
+    #[serde(rename = "x86-64-v2")]
+    X86_64V2,
+    /// x86-64-v2 with AVX, AVX2, BMI1, BMI2, F16C, FMA, LZCNT, MOVBE and XSAVE
+    #[value(name = "x86-64-v3")]
+    #[serde(rename = "x86-64-v3")]
+    X86_64V3,
+    /// x86-64-v3 with AVX512F, AVX512BW, AVX512CD, AVX512DQ and AVX512VL
+    #[value(name = "x86-64-v4")]
+    #[serde(rename = "x86-64-v4")]
+    X86_64V4,
+}
+
+/// The CPU features kept or hidden from the guest kernel, as (kept, feature
+/// name).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ClearCpuidFeatures(pub Vec<(bool, String)>);
+
+fn parse_clearcpuid_features(features: &str) -> Result<ClearCpuidFeatures, String> {
+    features
+        .split(',')
+        .map(|feature| {
+            let (kept, name) = match (feature.strip_prefix('+'), feature.strip_prefix('-')) {
+                (Some(name), _) => (true, name),
+                (_, Some(name)) => (false, name),
+                _ => (false, ""),
+            };
+            if name.is_empty() {
+                return Err(format!(
+                    "invalid CPU feature {feature:?}, expected +<feature> or -<feature>"
+                ));
+            }
+            Ok((kept, name.to_string()))
+        })
+        .collect::<Result<_, _>>()
+        .map(ClearCpuidFeatures)
+}
+
+/// The host CPUs of some vCPUs, as (vCPU index, host CPU).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct VcpuPinning(pub Vec<(u8, usize)>);
+
+fn parse_vcpu_pinning(pinning: &str) -> Result<VcpuPinning, String> {
+    pinning
+        .split(',')
+        .map(|pin| {
+            let (vcpu, cpu) = pin.split_once(':').ok_or_else(|| {
+                format!("invalid vCPU pinning {pin:?}, expected <vcpu>:<host cpu>")
+            })?;
+            let vcpu = vcpu
+                .parse()
+                .map_err(|_| format!("invalid vCPU index {vcpu:?}"))?;
+            let cpu = cpu
+                .parse()
+                .map_err(|_| format!("invalid host CPU {cpu:?}"))?;
+            Ok((vcpu, cpu))
+        })
+        .collect::<Result<_, _>>()
+        .map(VcpuPinning)
+}
+
+/// How the vCPUs are spread over the host CPUs.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityPolicy {
+    /// Fill the CPUs of a NUMA node before the next one
+    Pack,
+    /// Place the vCPUs on the NUMA nodes in turn
+    Spread,
+}
+
 #[derive(Args, Debug, Serialize, Deserialize, Clone)]
 pub struct MemArgs {
     #[clap(
@@ -284,6 +551,413 @@ pub struct MemArgs {
         display_order = 2
     )]
     pub mem_size: usize,
+    #[clap(
+        long,
+        value_parser,
+        help = "The host NUMA nodes to place the guest memory on, e.g. 0 or 0-1",
+        display_order = 2
+    )]
+    pub mem_host_nodes: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "How the guest memory is placed on --mem-host-nodes, bind by default",
+        display_order = 2
+    )]
+    pub mem_policy: Option<MemPolicy>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "2M",
+        help = "The size of the hugepages with --mem-type hugetlbfs",
+        display_order = 2
+    )]
+    pub hugepage_size: HugepageSize,
+    #[clap(
+        long,
+        value_parser,
+        help = "Fault in the guest memory before the VM starts",
+        display_order = 2
+    )]
+    pub mem_prefault: bool,
+    #[clap(
+        long,
+        value_parser,
+        help = "Lock the guest memory in RAM for the lifetime of the VM",
+        display_order = 2
+    )]
+    pub mem_lock: bool,
+}
+
+/// The NUMA memory policies, as in set_mempolicy(2).
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MemPolicy {
+    /// Allocate on the nodes only
+    Bind,
+    /// Allocate on the node if possible, elsewhere otherwise
+    Preferred,
+    /// Interleave the pages over the nodes
+    Interleave,
+}
+
+/// The hugepage sizes of the guest memory.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HugepageSize {
+    #[value(name = "2M")]
+    #[serde(rename = "2M")]
+    Size2M,
+    #[value(name = "1G")]
+    #[serde(rename = "1G")]
+    Size1G,
+}
+
+/// What dbs-cli does when the guest kernel panics.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Stop the VM and exit with an error
+    Exit,
+    /// Keep the VM in the panicked state for debugging
+    Pause,
+    /// Let the guest reboot
+    Restart,
+    /// Write the console ring buffer and the VM status to the dump file, then exit
+    Dump,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct GuestPanicArgs {
+    #[clap(
+        long,
+        value_enum,
+        help = "What to do when the guest kernel panics, the guest panic= boot arg is set accordingly [default: log the panic only]",
+        display_order = 2
+    )]
+    pub on_panic: Option<OnPanic>,
+    #[clap(
+        long,
+        value_parser,
+        default_value = "dbs-cli-panic.json",
+        help = "The file written on a guest kernel panic with --on-panic dump",
+        display_order = 2
+    )]
+    pub panic_dump_file: String,
+}
+
+/// What dbs-cli does when the guest reboots.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OnReboot {
+    /// Exit, as Dragonball stops the VM on a guest reboot
+    Exit,
+    /// Start a fresh VM with the same configuration
+    Restart,
+}
+
+/// When dbs-cli restarts a VM which stopped by itself.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Restart whenever the VM stops, unless stopped by dbs-cli
+    Always,
+    /// Restart when the VM fails, at most the given number of times
+    OnFailure(Option<u32>),
+}
+
+fn parse_restart_policy(policy: &str) -> Result<RestartPolicy, String> {
+    match policy.split_once(':') {
+        None if policy == "always" => Ok(RestartPolicy::Always),
+        None if policy == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+        Some(("on-failure", max)) => max
+            .parse()
+            .map(|max| RestartPolicy::OnFailure(Some(max)))
+            .map_err(|_| format!("invalid number of restarts {max:?}")),
+        _ => Err("the restart policy is always, on-failure or on-failure:N".to_string()),
+    }
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct RestartArgs {
+    #[clap(
+        long,
+        value_enum,
+        default_value = "exit",
+        help = "What to do when the guest reboots",
+        display_order = 2
+    )]
+    pub on_reboot: OnReboot,
+    #[clap(
+        long,
+        value_parser = parse_restart_policy,
+        help = "Restart the VM when it stops by itself: always, on-failure or on-failure:N for at most N restarts [default: never]",
+        display_order = 2
+    )]
+    pub restart_policy: Option<RestartPolicy>,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct DaemonArgs {
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Run in the background once the VM is ready, with stdio redirected to /dev/null (requires a serial path other than stdio)",
+        display_order = 2
+    )]
+    pub daemonize: bool,
+    #[clap(
+        long,
+        value_parser,
+        help = "The file the pid of dbs-cli is written to",
+        display_order = 2
+    )]
+    pub pid_file: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The fd READY=1 is written to once the VM has started and the api server is listening",
+        display_order = 2
+    )]
+    pub ready_fd: Option<i32>,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10,
+        help = "The seconds to wait for the VMM to stop the VM on SIGTERM or SIGINT, which is an immediate stop without a guest shutdown, before exiting anyway",
+        display_order = 2
+    )]
+    pub stop_timeout: u64,
+}
+
+/// The built-in seccomp filters.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompLevel {
+    /// No seccomp filter
+    None,
+    /// Allow the syscalls dbs-cli uses, for the VMM and vCPU threads alike
+    Basic,
+    /// Allow fewer syscalls for the vCPU threads, and the known ioctls only
+    Strict,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct SeccompArgs {
+    #[clap(
+        long,
+        value_enum,
+        default_value = "basic",
+        help = "The built-in seccomp filters of the VMM and vCPU threads",
+        display_order = 2
+    )]
+    pub seccomp_level: SeccompLevel,
+    #[clap(
+        long,
+        value_parser,
+        help = "A seccompiler JSON file with the \"vmm\" and \"vcpu\" filters, instead of the built-in ones",
+        display_order = 2
+    )]
+    pub seccomp_filter: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Log the syscalls the built-in seccomp filters would deny to the kernel audit log, instead of denying them",
+        display_order = 2
+    )]
+    pub seccomp_audit: bool,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct DigestArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The SHA-256 digest the kernel has to match to boot",
+        display_order = 2
+    )]
+    pub kernel_sha256: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The SHA-256 digest the initrd has to match to boot",
+        display_order = 2
+    )]
+    pub initrd_sha256: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_drive_digest,
+        help = "The SHA-256 digest the rootfs or a drive of --virblks has to match, as <path>=<sha256> (can be given several times)",
+        display_order = 2
+    )]
+    pub drive_sha256: Vec<(String, String)>,
+    #[clap(
+        long,
+        value_parser,
+        help = "A file of SHA-256 digests in the sha256sum format, which all the boot artifacts have to be listed in and match (requires --digest-manifest-sig and --digest-manifest-key)",
+        display_order = 2
+    )]
+    pub digest_manifest: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The detached signature of --digest-manifest, as made by openssl dgst -sha256 -sign",
+        display_order = 2
+    )]
+    pub digest_manifest_sig: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The trusted public key in PEM the signature of --digest-manifest is verified with",
+        display_order = 2
+    )]
+    pub digest_manifest_key: Option<String>,
+}
+
+fn parse_drive_digest(digest: &str) -> Result<(String, String), String> {
+    match digest.rsplit_once('=') {
+        Some((path, sha256)) if !path.is_empty() => Ok((path.to_string(), sha256.to_string())),
+        _ => Err("the drive digest is <path>=<sha256>".to_string()),
+    }
+}
+
+fn parse_cgroup_setting(setting: &str) -> Result<(String, String), String> {
+    match setting.split_once('=') {
+        Some((key, value)) if key.contains('.') && !key.contains('/') => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => {
+            Err("the cgroup setting is <controller>.<key>=<value>, e.g. memory.max=1G".to_string())
+        }
+    }
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct JailArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The uid the VMM runs as in the jail",
+        display_order = 1
+    )]
+    pub uid: u32,
+    #[clap(
+        long,
+        value_parser,
+        help = "The gid the VMM runs as in the jail",
+        display_order = 1
+    )]
+    pub gid: u32,
+    #[clap(
+        long,
+        value_parser,
+        default_value = "/srv/dbs-cli",
+        help = "The directory the jails are created in, as <chroot-base-dir>/<name>/root",
+        display_order = 1
+    )]
+    pub chroot_base_dir: String,
+    #[clap(
+        long,
+        value_parser = parse_cgroup_setting,
+        help = "A cgroup v2 setting of the jail, e.g. --cgroup cpu.max=\"50000 100000\" --cgroup memory.max=1G",
+        display_order = 1
+    )]
+    pub cgroup: Vec<(String, String)>,
+    #[clap(
+        long,
+        value_parser,
+        default_value = "dbs-cli",
+        help = "The cgroup the cgroups of the jails are created in, relative to /sys/fs/cgroup",
+        display_order = 1
+    )]
+    pub cgroup_parent: String,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct CgroupArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The cgroup v2 to run dbs-cli in, relative to /sys/fs/cgroup, created if it does not exist and removed on exit then",
+        display_order = 2
+    )]
+    pub cgroup_path: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The cpu.max of the cgroup, e.g. \"200000 100000\" for 2 CPUs",
+        display_order = 2
+    )]
+    pub cgroup_cpu_max: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The cpuset.cpus of the cgroup, e.g. 0-3",
+        display_order = 2
+    )]
+    pub cgroup_cpuset: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        help = "The memory.max of the cgroup, e.g. 2G",
+        display_order = 2
+    )]
+    pub cgroup_memory_max: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Move the vCPU threads into the threaded child cgroup vcpus, apart from the VMM and I/O threads",
+        display_order = 2
+    )]
+    pub cgroup_vcpu: bool,
+}
+
+/// The jail `dbs-cli create` enters before creating the VM.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JailRoot {
+    pub root: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct ConsoleLogArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The file the guest console output is written to, besides the interactive console",
+        display_order = 2
+    )]
+    pub console_log_file: Option<String>,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Prefix each line of the console log file with the host time",
+        display_order = 2
+    )]
+    pub console_log_timestamps: bool,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10240,
+        help = "Rotate the console log file when it exceeds this size in KiB, 0 to never rotate",
+        display_order = 2
+    )]
+    pub console_log_max_size_kb: u64,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5,
+        help = "The number of rotated console log files to keep",
+        display_order = 2
+    )]
+    pub console_log_max_files: usize,
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 64,
+        help = "The size in KiB of the in-memory ring buffer of the console output, which can be read with the get command",
+        display_order = 2
+    )]
+    pub console_ring_size_kb: usize,
 }
 
 #[derive(Args, Debug, Serialize, Deserialize, Clone)]
@@ -312,6 +986,14 @@ pub struct HostDeviceArgs {
 
 #[derive(Args, Debug, Serialize, Deserialize, Clone)]
 pub struct UpdateArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The name of the VM, instead of --api-sock-path",
+        display_order = 1
+    )]
+    pub name: Option<String>,
+
     #[clap(
         long,
         value_parser,
@@ -320,6 +1002,30 @@ pub struct UpdateArgs {
     )]
     pub vcpu_resize: Option<usize>,
 
+    #[clap(
+        long,
+        value_parser,
+        help = "Change the cpu.max of the cgroup of the VM, e.g. along with --vcpu-resize",
+        display_order = 2
+    )]
+    pub cgroup_cpu_max: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Change the cpuset.cpus of the cgroup of the VM",
+        display_order = 2
+    )]
+    pub cgroup_cpuset: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Change the memory.max of the cgroup of the VM",
+        display_order = 2
+    )]
+    pub cgroup_memory_max: Option<String>,
+
     #[clap(
         long,
         value_parser,
@@ -340,6 +1046,33 @@ The type of it is an array of BlockDeviceConfigInfo, e.g.
     )]
     pub virblks: Option<String>,
 
+    #[clap(
+        long,
+        value_parser = parse_drive_digest,
+        help = "The SHA-256 digest a drive of --virblks has to match to be inserted, as <path>=<sha256> (can be given several times)",
+        display_order = 2
+    )]
+    pub drive_sha256: Vec<(String, String)>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = r#"Insert hotplug vhost-user-blk devices into the Dragonball.
+The type of it is an array of VhostUserBlkConfig, e.g.
+    --vhost-user-blks '[{"drive_id":"vub0","sock_path":"/tmp/vhost-blk.sock","num_queues":1,"queue_size":128}]'"#,
+        display_order = 2
+    )]
+    pub vhost_user_blks: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = r#"Insert a vhost-user-fs device into the Dragonball using the VhostUserFsConfig, e.g.
+    --vhost-user-fs '{"tag":"myfs","sock_path":"/tmp/virtiofsd.sock","num_queues":1,"queue_size":1024}'"#,
+        display_order = 2
+    )]
+    pub vhost_user_fs: Option<String>,
+
     #[clap(
         long,
         value_parser,
@@ -367,3 +1100,204 @@ The type of it is an array of BlockDeviceConfigInfo, e.g.
     )]
     pub remove_host_device: Option<String>,
 }
+
+/// Configurations of the passt launcher for user-mode networking.
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct NetdArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The sock_path of the vhost-user-net device to serve",
+        display_order = 1
+    )]
+    pub sock_path: String,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Forward a TCP port of the host to the guest, e.g. 8080:80 (can be given several times)",
+        display_order = 2
+    )]
+    pub tcp_forward: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Forward a UDP port of the host to the guest, e.g. 5353:53 (can be given several times)",
+        display_order = 2
+    )]
+    pub udp_forward: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "passt",
+        help = "The path of passt, which does the NAT and the port forwarding on the host's sockets",
+        display_order = 3
+    )]
+    pub passt_path: String,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10,
+        help = "Seconds to wait for the socket of each passt to appear",
+        display_order = 3
+    )]
+    pub connect_timeout: u64,
+}
+
+/// Configurations used for attaching to the console of a VM.
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct ConsoleArgs {
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value = "",
+        help = "The serial path the VM was created with",
+        display_order = 1
+    )]
+    pub serial_path: String,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The name of the VM, instead of --serial-path",
+        display_order = 1
+    )]
+    pub name: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Observe the console without writing to it, which works while another client is attached",
+        display_order = 2
+    )]
+    pub read_only: bool,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct GetArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The name of the VM, instead of --api-sock-path",
+        display_order = 1
+    )]
+    pub name: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the vCPUs and the memory of the VM",
+        display_order = 2
+    )]
+    pub info: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the last console output of the VM kept in memory",
+        display_order = 2
+    )]
+    pub console_log: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The size in KiB of the console output to print, the whole ring buffer by default",
+        display_order = 2
+    )]
+    pub console_log_size_kb: Option<usize>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the host CPUs each vCPU thread may run on",
+        display_order = 2
+    )]
+    pub vcpu_affinity: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the CPU features hidden from the guest kernel with clearcpuid=, and the CPUID the host supports",
+        display_order = 2
+    )]
+    pub cpuid: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the SHA-256 digests of the kernel, initrd and drives the VM booted",
+        display_order = 2
+    )]
+    pub digests: bool,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct CheckHostArgs {
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        help = "Print the results in JSON instead of a table",
+        display_order = 1
+    )]
+    pub json: bool,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize, Clone)]
+pub struct ShutdownArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The name of the VM, instead of --api-sock-path",
+        display_order = 1
+    )]
+    pub name: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clearcpuid_features() {
+        assert_eq!(
+            parse_clearcpuid_features("+avx2,-tsx"),
+            Ok(ClearCpuidFeatures(vec![
+                (true, "avx2".to_string()),
+                (false, "tsx".to_string())
+            ]))
+        );
+        assert!(parse_clearcpuid_features("").is_err());
+        assert!(parse_clearcpuid_features("avx2").is_err());
+        assert!(parse_clearcpuid_features("+").is_err());
+        assert!(parse_clearcpuid_features("+avx2,").is_err());
+        assert!(parse_clearcpuid_features("é").is_err());
+    }
+
+    #[test]
+    fn test_parse_vcpu_pinning() {
+        assert_eq!(
+            parse_vcpu_pinning("0:2,1:3"),
+            Ok(VcpuPinning(vec![(0, 2), (1, 3)]))
+        );
+        assert_eq!(parse_vcpu_pinning("5:0"), Ok(VcpuPinning(vec![(5, 0)])));
+        assert!(parse_vcpu_pinning("").is_err());
+        assert!(parse_vcpu_pinning("0").is_err());
+        assert!(parse_vcpu_pinning("0:2,").is_err());
+        assert!(parse_vcpu_pinning("a:2").is_err());
+        assert!(parse_vcpu_pinning("0:-1").is_err());
+        // the vCPU index is a u8.
+        assert!(parse_vcpu_pinning("256:0").is_err());
+    }
+}
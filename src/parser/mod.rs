@@ -3,88 +3,372 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    fs::OpenOptions,
-    os::unix::io::IntoRawFd,
+    os::unix::io::{AsRawFd, RawFd},
+    panic::{self, AssertUnwindSafe},
     sync::{Arc, Mutex},
     thread,
+    time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use crossbeam_channel::unbounded;
-use dragonball::{api::v1::VmmService, Vmm};
+use dragonball::{
+    api::v1::{InstanceState, VmmService},
+    Vmm,
+};
 
+use crate::affinity::VcpuAffinity;
 use crate::api_server::ApiServer;
+use crate::cgroup::VmCgroup;
+use crate::clearcpuid::ClearCpuid;
 use crate::cli_instance::CliInstance;
-use crate::parser::args::CreateArgs;
+use crate::console_client::RawTerminal;
+use crate::console_log::ConsoleLog;
+use crate::console_server::ConsoleServer;
+use crate::cpu_topology;
+use crate::daemon::{self, PidFile, Readiness};
+use crate::digests;
+use crate::guest_mac::GuestMacTable;
+use crate::guest_net;
+use crate::guest_panic::GuestPanicHandler;
+use crate::hugepages;
+use crate::netns::{self, TcRedirect};
+use crate::numa;
+use crate::parser::args::{CreateArgs, OnPanic};
+use crate::registry::{self, VmEntry};
+use crate::restart::{HotplugRecord, Restarter};
+use crate::runtime_dir::{self, CleanupGuard};
+use crate::seccomp;
+use crate::signal_handler::{self, SignalHandler};
+use crate::stdio_console::{self, StdioConsole};
+use crate::utils;
 
 pub mod args;
 
-const KVM_DEVICE: &str = "/dev/kvm";
+/// The pause before restarting a stopped VM, so that a VM failing at boot
+/// does not spin.
+const RESTART_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn run_with_cli(
+    mut create_args: CreateArgs,
+    api_sock_path: &String,
+    state_dir: &str,
+) -> Result<i32> {
+    // the runtime directory and the files dbs-cli created are removed when it
+    // returns.
+    let _cleanup = CleanupGuard;
+    // the errors of the arguments are printed before dbs-cli is daemonized.
+    // a single /dev/kvm handle serves the checks and each VMM of a restart.
+    let kvm = Arc::new(utils::open_kvm()?);
+    cpu_topology::validate(&mut create_args.cpu, kvm.get_max_vcpus())?;
+    digests::resolve(&mut create_args)?;
+    // the vCPU threads, which touch the guest memory first, inherit the
+    // policy, as does a daemon.
+    numa::set_mem_policy(&create_args.mem)?;
+    // the hugepages are checked on the host before a jail is entered.
+    if create_args.jail.is_none() {
+        hugepages::check_free(&create_args.mem)?;
+    }
+    // fork before any thread is spawned.
+    let mut ready_fds: Vec<RawFd> = create_args.daemon.ready_fd.into_iter().collect();
+    if create_args.daemon.daemonize {
+        if create_args.serial_path == "stdio" {
+            return Err(anyhow!(
+                "--daemonize requires --serial-path other than stdio"
+            ));
+        }
+        ready_fds.push(daemon::daemonize()?);
+    }
+    let _pid_file = match &create_args.daemon.pid_file {
+        Some(pid_file) => Some(PidFile::create(pid_file)?),
+        None => None,
+    };
+    let readiness = Readiness::new(ready_fds);
+    hugepages::setup_mem_file(&mut create_args.mem)?;
+    // the threads of dbs-cli are created in its cgroup.
+    let vm_cgroup = VmCgroup::setup(&create_args.cgroup)?.map(Arc::new);
+    if let Some(vm_cgroup) = vm_cgroup.clone() {
+        runtime_dir::on_exit(move || vm_cgroup.cleanup());
+    }
+    let vcpu_affinity = VcpuAffinity::new(&create_args.cpu)?.map(Arc::new);
+    let clearcpuid = ClearCpuid::new(&create_args.cpu, &kvm)?.map(Arc::new);
+    if let Some(clearcpuid) = &clearcpuid {
+        create_args.boot_args = clearcpuid.boot_args(&create_args.boot_args);
+    }
+    signal_handler::block_signals()?;
+
+    // enter the network namespace before any thread is spawned, so that the
+    // tap devices are opened by the VMM inside of it.
+    if let Some(netns_path) = &create_args.netns {
+        netns::enter_netns(netns_path)?;
+    }
+
+    // the tap device has to exist before the VMM opens it.
+    if let Some(veth) = &create_args.net_tc_redirect {
+        setup_tc_redirect(&create_args.virnets, veth)?;
+    }
 
-pub fn run_with_cli(create_args: CreateArgs, api_sock_path: &String) -> Result<i32> {
-    let mut cli_instance = CliInstance::new("dbs-cli");
+    let vm_id = registry::vm_id(&create_args);
+    if let Some(name) = &create_args.name {
+        registry::register(
+            state_dir,
+            &VmEntry::new(name, &vm_id, api_sock_path, &create_args),
+        )?;
+    }
 
-    let kvm = OpenOptions::new().read(true).write(true).open(KVM_DEVICE)?;
+    let mut cli_instance = CliInstance::new(&vm_id);
+    (cli_instance.vmm_seccomp, cli_instance.vcpu_seccomp) =
+        seccomp::build_filters(&create_args.seccomp)?;
 
     let (to_vmm, from_runtime) = unbounded();
     let (to_runtime, from_vmm) = unbounded();
 
-    let vmm_service = VmmService::new(from_runtime, to_runtime);
-
     cli_instance.to_vmm = Some(to_vmm);
     cli_instance.from_vmm = Some(Arc::new(Mutex::new(from_vmm)));
 
-    let api_event_fd2 = cli_instance
-        .to_vmm_fd
-        .try_clone()
-        .expect("Failed to dup eventfd");
-    let vmm = Vmm::new(
-        cli_instance.vmm_shared_info.clone(),
-        api_event_fd2,
-        cli_instance.seccomp.clone(),
-        cli_instance.seccomp.clone(),
-        Some(kvm.into_raw_fd()),
-    )
-    .expect("Failed to start vmm");
-
     let api_event_fd3 = cli_instance
         .to_vmm_fd
         .try_clone()
         .expect("Failed to dup eventfd");
 
+    let console_log = ConsoleLog::new(&create_args.console_log)?;
+
+    let panic_handler = Arc::new(GuestPanicHandler::new(
+        cli_instance.vmm_shared_info.read().unwrap().id.clone(),
+        cli_instance.vmm_shared_info.clone(),
+        cli_instance.to_vmm.clone(),
+        cli_instance.from_vmm.clone(),
+        cli_instance
+            .to_vmm_fd
+            .try_clone()
+            .expect("Failed to dup eventfd"),
+        create_args.guest_panic.clone(),
+    ));
+    console_log.set_panic_handler(panic_handler.clone());
+
+    let hotplug_record = HotplugRecord::default();
     let mut api_server = ApiServer::new(
         cli_instance.to_vmm.clone(),
         cli_instance.from_vmm.clone(),
         api_event_fd3,
+        cli_instance.vmm_shared_info.read().unwrap().id.clone(),
+        cli_instance.guest_macs.clone(),
+        console_log.clone(),
+        hotplug_record.clone(),
     );
+    api_server.vm_cgroup = vm_cgroup.clone();
+    api_server.vcpu_affinity = vcpu_affinity.clone();
+    api_server.clearcpuid = clearcpuid;
+    api_server.kvm = Some(kvm.clone());
+    api_server.measured_digests = cli_instance.measured_digests.clone();
 
-    // clone the arguments for other thread to use
-    let clone_args = create_args.clone();
+    let signal_handler = SignalHandler::new(
+        cli_instance.vmm_shared_info.clone(),
+        cli_instance.to_vmm.clone(),
+        cli_instance.from_vmm.clone(),
+        cli_instance
+            .to_vmm_fd
+            .try_clone()
+            .expect("Failed to dup eventfd"),
+        create_args.clone(),
+        hotplug_record.clone(),
+    );
     thread::Builder::new()
-        .name("set_cfg".to_owned())
+        .name("signal_handler".to_owned())
         .spawn(move || {
+            signal_handler
+                .run_signal_handler()
+                .expect("Failed to run signal handler.");
+        })
+        .unwrap();
+
+    // the stdio console talks to the VMM as well.
+    let stdio_console = (create_args.serial_path == "stdio").then(|| {
+        StdioConsole::new(
+            cli_instance.vmm_shared_info.clone(),
+            cli_instance.to_vmm.clone(),
+            cli_instance.from_vmm.clone(),
             cli_instance
-                .run_vmm_server(clone_args)
-                .expect("Failed to run server.");
+                .to_vmm_fd
+                .try_clone()
+                .expect("Failed to dup eventfd"),
+            console_log.clone(),
+        )
+    });
+
+    // the API socket is in the runtime directory unless given.
+    let clone_api_sock_path = api_sock_path.to_string();
+    let api_readiness = readiness.clone();
+    thread::Builder::new()
+        .name("api_server".to_owned())
+        .spawn(move || {
+            let result = api_server.run_api_server(clone_api_sock_path, api_readiness.clone());
+            if result.is_err() {
+                api_readiness.set_failed();
+            }
+            result.expect("Failed to api server.");
         })
         .unwrap();
 
-    if !api_sock_path.is_empty() {
-        let clone_api_sock_path = api_sock_path.to_string().clone();
+    // the terminal is restored when dbs-cli exits or panics.
+    let mut _raw_terminal = None;
+    if let Some(stdio_console) = stdio_console {
+        let vmm_sock_path = stdio_console::vmm_sock_path()?;
+        runtime_dir::remove_stale_socket(&vmm_sock_path)?;
+        runtime_dir::register(&vmm_sock_path);
+        _raw_terminal = RawTerminal::new(std::io::stdin().as_raw_fd())?;
+        if let Some(raw_terminal) = &_raw_terminal {
+            raw_terminal.restore_on_panic();
+            raw_terminal.restore_on_exit();
+        }
+
         thread::Builder::new()
-            .name("api_server".to_owned())
+            .name("stdio_console".to_owned())
             .spawn(move || {
-                api_server
-                    .run_api_server(clone_api_sock_path)
-                    .expect("Failed to api server.");
+                stdio_console
+                    .run_stdio_console(vmm_sock_path)
+                    .expect("Failed to run stdio console.");
             })
             .unwrap();
     } else {
-        println!("Warning: api server is not created because --api-sock-path is not provided when creating VM. Update command is not supported.");
+        let mut console_server = ConsoleServer::new(create_args.serial_path.clone(), console_log);
+        thread::Builder::new()
+            .name("console_server".to_owned())
+            .spawn(move || {
+                console_server
+                    .run_console_server()
+                    .expect("Failed to run console server.");
+            })
+            .unwrap();
+    }
+
+    // a restarted VM is a fresh Dragonball VM, which shares the channels and
+    // the instance info with the API server and the consoles. Each VMM runs
+    // on a thread of its own, which Dragonball applies the VMM seccomp filter
+    // to, so that the main thread restarts the VM and cleans up on exit
+    // without the filter.
+    let mut restarter = Restarter::new(&create_args.restart);
+    let mut restarted = false;
+    hugepages::lock_memory(&create_args.mem)?;
+    let exit_code = loop {
+        if restarted {
+            hugepages::relock_prefaulted(&create_args.mem)?;
+        }
+        // the VMM owns and closes the fd it is given.
+        let kvm_fd =
+            nix::unistd::dup(kvm.as_raw_fd()).context("Failed to duplicate the /dev/kvm fd")?;
+        let api_event_fd2 = cli_instance
+            .to_vmm_fd
+            .try_clone()
+            .expect("Failed to dup eventfd");
+        let vmm_shared_info = cli_instance.vmm_shared_info.clone();
+        let vmm_seccomp = cli_instance.vmm_seccomp.clone();
+        let vcpu_seccomp = cli_instance.vcpu_seccomp.clone();
+        let vmm_service = VmmService::new(from_runtime.clone(), to_runtime.clone());
+        let vmm_thread = thread::Builder::new()
+            .name("vmm_master".to_owned())
+            .spawn(move || {
+                let vmm = Vmm::new(
+                    vmm_shared_info,
+                    api_event_fd2,
+                    vmm_seccomp,
+                    vcpu_seccomp,
+                    Some(kvm_fd),
+                )
+                .expect("Failed to start vmm");
+                Vmm::run_vmm_event_loop(Arc::new(Mutex::new(vmm)), vmm_service)
+            })
+            .context("Failed to spawn the VMM thread")?;
+
+        // clone the arguments for other thread to use
+        let clone_args = create_args.clone();
+        let vmm_server = cli_instance.try_clone()?;
+        let clone_api_sock_path = api_sock_path.to_string();
+        let hotplug_record = hotplug_record.clone();
+        let readiness = readiness.clone();
+        let vm_cgroup = vm_cgroup.clone();
+        let vcpu_affinity = vcpu_affinity.clone();
+        let vcpu_count = create_args.cpu.vcpu as usize;
+        let mem_args = create_args.mem.clone();
+        thread::Builder::new()
+            .name("set_cfg".to_owned())
+            .spawn(move || {
+                // the parent of a daemon waits for the VM to start or fail.
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| vmm_server.run_vmm_server(clone_args)));
+                match result {
+                    Ok(Ok(())) => readiness.set_vm_started(),
+                    Ok(Err(err)) => {
+                        readiness.set_failed();
+                        panic!("Failed to run server.: {err:?}");
+                    }
+                    Err(err) => {
+                        readiness.set_failed();
+                        panic::resume_unwind(err);
+                    }
+                }
+                hugepages::unlock_prefaulted(&mem_args);
+                if let Some(vm_cgroup) = &vm_cgroup {
+                    vm_cgroup.place_vcpu_threads(vcpu_count);
+                }
+                if let Some(vcpu_affinity) = &vcpu_affinity {
+                    vcpu_affinity.apply(vcpu_count);
+                }
+                if restarted {
+                    if let Err(err) = hotplug_record.replay(&clone_api_sock_path) {
+                        log::warn!("Failed to restore the hotplugged devices: {:?}", err);
+                    }
+                }
+            })
+            .unwrap();
+
+        let exit_code = vmm_thread
+            .join()
+            .map_err(|_| anyhow!("the VMM thread panicked"))?;
+
+        let panic_restart =
+            panic_handler.panicked() && create_args.guest_panic.on_panic == Some(OnPanic::Restart);
+        if !restarter.should_restart(exit_code, panic_restart) {
+            break exit_code;
+        }
+        log::warn!("the VM stopped with exit code {}, restarting it", exit_code);
+        panic_handler.reset();
+        cli_instance.guest_macs.clear();
+        cli_instance.vmm_shared_info.write().unwrap().state = InstanceState::Uninitialized;
+        restarted = true;
+        thread::sleep(RESTART_INTERVAL);
+    };
+
+    if panic_handler.panicked()
+        && matches!(
+            create_args.guest_panic.on_panic,
+            Some(OnPanic::Exit) | Some(OnPanic::Dump)
+        )
+    {
+        return Err(anyhow!("the guest kernel panicked"));
     }
 
-    Ok(Vmm::run_vmm_event_loop(
-        Arc::new(Mutex::new(vmm)),
-        vmm_service,
-    ))
+    Ok(exit_code)
+}
+
+/// Create the tap device of the first tap-based net device and wire it to `veth`.
+fn setup_tc_redirect(virnets: &str, veth: &str) -> Result<()> {
+    // only the tap devices matter here, the guest MAC addresses are checked
+    // again when the devices are inserted.
+    let net_devices = if virnets.is_empty() {
+        vec![]
+    } else {
+        guest_net::parse_net_configs(virnets, "", &GuestMacTable::default())?
+    };
+    let (config, tap) = net_devices
+        .iter()
+        .find_map(|dev| utils::net_tap_name(&dev.config).map(|tap| (&dev.config, tap)))
+        .ok_or_else(|| {
+            anyhow!("--net-tc-redirect requires a virtio-net or vhost-net device in --virnets")
+        })?;
+    // a device with more than one pair of rx/tx queues opens the tap in multi-queue mode.
+    let multi_queue = config.num_queues.unwrap_or(2) > 2;
+
+    TcRedirect::setup(tap, veth, multi_queue)
+        .with_context(|| format!("Failed to redirect traffic between {veth} and {tap}"))
 }
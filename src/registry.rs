@@ -0,0 +1,231 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api_client::send_query;
+use crate::guest_mac;
+use crate::parser::args::CreateArgs;
+use crate::runtime_dir;
+use crate::utils;
+
+/// A VM created with `--name`, registered in the state directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmEntry {
+    pub name: String,
+    pub id: String,
+    pub pid: i32,
+    /// the start time of the process in /proc/<pid>/stat, which tells it
+    /// apart from a later process reusing the pid
+    #[serde(default)]
+    pub pid_start_time: Option<u64>,
+    pub api_sock_path: String,
+    pub serial_path: String,
+    pub start_time: String,
+    pub config: CreateArgs,
+}
+
+impl VmEntry {
+    pub fn new(name: &str, id: &str, api_sock_path: &str, config: &CreateArgs) -> Self {
+        let pid = std::process::id() as i32;
+        VmEntry {
+            name: name.to_string(),
+            id: id.to_string(),
+            pid,
+            pid_start_time: process_start_time(pid),
+            api_sock_path: api_sock_path.to_string(),
+            serial_path: config.serial_path.clone(),
+            start_time: utils::utc_timestamp(),
+            config: config.clone(),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        // EPERM means the process exists, but belongs to another user.
+        if !matches!(
+            kill(Pid::from_raw(self.pid), None),
+            Ok(()) | Err(Errno::EPERM)
+        ) {
+            return false;
+        }
+        // a process started later has the pid of a dead VM.
+        match (self.pid_start_time, process_start_time(self.pid)) {
+            (Some(registered), Some(current)) => registered == current,
+            _ => true,
+        }
+    }
+}
+
+/// The start time of a process since the boot, in clock ticks, the 22nd
+/// field of /proc/<pid>/stat.
+fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the command name in parentheses may contain spaces.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The ID of the VM, which seeds the generated guest MAC addresses and so
+/// stays the same across starts: `--vm-id`, the name, or one derived from the
+/// kernel and the rootfs.
+pub fn vm_id(config: &CreateArgs) -> String {
+    if let Some(vm_id) = config.vm_id.as_ref().or(config.name.as_ref()) {
+        return vm_id.clone();
+    }
+    let canonical = |path: &Option<String>| {
+        path.as_deref()
+            .map(|path| {
+                std::fs::canonicalize(path)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| path.to_string())
+            })
+            .unwrap_or_default()
+    };
+    let seed = format!(
+        "{}\0{}",
+        canonical(&config.kernel_path),
+        canonical(&config.rootfs_args.rootfs)
+    );
+    format!("dbs-cli-{:016x}", guest_mac::fnv1a(seed.bytes()))
+}
+
+fn entry_path(state_dir: &str, name: &str) -> PathBuf {
+    Path::new(state_dir).join(format!("{name}.json"))
+}
+
+fn create_state_dir(state_dir: &str) -> Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(state_dir)
+        .with_context(|| format!("Failed to create the state directory {state_dir}"))
+}
+
+/// Register a VM, which is unregistered when dbs-cli exits.
+pub fn register(state_dir: &str, entry: &VmEntry) -> Result<()> {
+    if entry.name.is_empty() || entry.name.contains('/') || entry.name.starts_with('.') {
+        return Err(anyhow!("invalid VM name {:?}", entry.name));
+    }
+    create_state_dir(state_dir)?;
+    if let Ok(existing) = read_entry(&entry_path(state_dir, &entry.name)) {
+        if existing.is_alive() {
+            return Err(anyhow!(
+                "VM {} is already running with pid {}",
+                entry.name,
+                existing.pid
+            ));
+        }
+    }
+    let path = entry_path(state_dir, &entry.name);
+    std::fs::write(&path, serde_json::to_string_pretty(entry)?)
+        .with_context(|| format!("Failed to register the VM in {}", path.display()))?;
+    runtime_dir::register(path);
+    Ok(())
+}
+
+fn read_entry(path: &Path) -> Result<VmEntry> {
+    let content = std::fs::read_to_string(path)?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid entry {}", path.display()))
+}
+
+/// Find a running VM by name.
+pub fn lookup(state_dir: &str, name: &str) -> Result<VmEntry> {
+    let path = entry_path(state_dir, name);
+    let entry = read_entry(&path).map_err(|_| anyhow!("VM {name} is not registered"))?;
+    if !entry.is_alive() {
+        let _ = std::fs::remove_file(&path);
+        return Err(anyhow!("VM {name} is not running any more"));
+    }
+    Ok(entry)
+}
+
+/// Get the API socket of the VM named `name`, or `api_sock_path` without a name.
+pub fn resolve_api_sock_path(
+    state_dir: &str,
+    name: &Option<String>,
+    api_sock_path: &str,
+) -> Result<String> {
+    match name {
+        Some(name) => Ok(lookup(state_dir, name)?.api_sock_path),
+        None => Ok(api_sock_path.to_string()),
+    }
+}
+
+/// Get the running VMs, and remove the entries of the dead ones. The entries
+/// which cannot be read are skipped.
+fn running_vms(state_dir: &str) -> Result<Vec<VmEntry>> {
+    let dir = match std::fs::read_dir(state_dir) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read the state directory {state_dir}"))
+        }
+    };
+    let mut entries = vec![];
+    for dir_entry in dir {
+        let path = dir_entry?.path();
+        if path.extension() != Some(std::ffi::OsStr::new("json")) {
+            continue;
+        }
+        match read_entry(&path) {
+            Ok(entry) if entry.is_alive() => entries.push(entry),
+            Ok(_) => {
+                let _ = std::fs::remove_file(&path);
+            }
+            Err(err) => log::warn!("Skipped the VM entry {}: {:?}", path.display(), err),
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Print the running VMs with their vCPUs and memory.
+pub fn run_list(state_dir: &str) -> Result<()> {
+    println!(
+        "{:<16} {:<8} {:<6} {:<10} {:<28} API SOCKET",
+        "NAME", "PID", "VCPUS", "MEMORY", "STARTED"
+    );
+    for entry in running_vms(state_dir)? {
+        // the vCPUs may have been resized since the VM was created.
+        let info = send_query(json!({ "action": "get_vm_info" }), &entry.api_sock_path).ok();
+        let field = |key: &str| {
+            info.as_ref()
+                .and_then(|info| info.get(key))
+                .map(|value| value.to_string())
+        };
+        let vcpus = field("vcpu_count").unwrap_or_else(|| entry.config.cpu.vcpu.to_string());
+        let memory = field("mem_size_mib").unwrap_or_else(|| entry.config.mem.mem_size.to_string());
+        println!(
+            "{:<16} {:<8} {:<6} {:<10} {:<28} {}",
+            entry.name,
+            entry.pid,
+            vcpus,
+            format!("{memory}MiB"),
+            entry.start_time,
+            entry.api_sock_path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_start_time() {
+        let pid = std::process::id() as i32;
+        assert!(process_start_time(pid).is_some());
+        assert_eq!(process_start_time(pid), process_start_time(pid));
+        assert_eq!(process_start_time(-1), None);
+    }
+}
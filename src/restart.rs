@@ -0,0 +1,116 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::api_client::send_request;
+use crate::parser::args::{OnReboot, RestartArgs, RestartPolicy};
+
+/// Whether the VM is stopped on purpose, by a shutdown request of dbs-cli.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Do not restart the VM once it stops.
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// The key marking a request replayed on a restarted VM, which is recorded
+/// already.
+const REPLAYED_KEY: &str = "replayed";
+
+/// The requests of the API server which changed the devices or the vCPUs of
+/// the VM, in order, so that a restarted VM gets them again.
+#[derive(Clone, Default)]
+pub struct HotplugRecord {
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl HotplugRecord {
+    /// Record a request the API server handled successfully.
+    pub fn record(&self, request: &Value) {
+        if request[REPLAYED_KEY] == true {
+            return;
+        }
+        let mut requests = self.requests.lock().unwrap();
+        match request["action"].as_str() {
+            Some("insert_host_device")
+            | Some("insert_virnets")
+            | Some("insert_virblks")
+            | Some("insert_vhost_user_blks")
+            | Some("insert_vhost_user_fs")
+            | Some("patch_fs") => requests.push(request.clone()),
+            // only the last vCPU count matters.
+            Some("resize_vcpu") => {
+                requests.retain(|r| r["action"] != "resize_vcpu");
+                requests.push(request.clone());
+            }
+            Some("remove_host_device") => requests.retain(|r| {
+                r["action"] != "insert_host_device" || r["hostdev-id"] != request["hostdev-id"]
+            }),
+            _ => {}
+        }
+    }
+
+    /// Get the recorded requests.
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Send the recorded requests to the API server again. They stay
+    /// recorded, so that a request failing now is tried again by the next
+    /// restart.
+    pub fn replay(&self, api_sock_path: &str) -> Result<()> {
+        for mut request in self.requests() {
+            let action = request["action"].to_string();
+            request[REPLAYED_KEY] = Value::Bool(true);
+            send_request(request, api_sock_path)
+                .with_context(|| format!("Failed to replay {action} on the restarted VM"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decide whether to restart the VM when the Dragonball event loop returns.
+pub struct Restarter {
+    on_reboot: OnReboot,
+    restart_policy: Option<RestartPolicy>,
+    failures: u32,
+}
+
+impl Restarter {
+    pub fn new(args: &RestartArgs) -> Self {
+        Restarter {
+            on_reboot: args.on_reboot,
+            restart_policy: args.restart_policy,
+            failures: 0,
+        }
+    }
+
+    /// Dragonball stops the VM on a guest reboot, which is told from a
+    /// failure by the exit code. `panic_restart` is set when the guest
+    /// rebooted after a panic with `--on-panic restart`.
+    pub fn should_restart(&mut self, exit_code: i32, panic_restart: bool) -> bool {
+        if STOP_REQUESTED.load(Ordering::SeqCst) {
+            return false;
+        }
+        if exit_code == 0 {
+            return panic_restart
+                || self.on_reboot == OnReboot::Restart
+                || self.restart_policy == Some(RestartPolicy::Always);
+        }
+        let restart = match self.restart_policy {
+            Some(RestartPolicy::Always) => true,
+            Some(RestartPolicy::OnFailure(Some(max))) => self.failures < max,
+            Some(RestartPolicy::OnFailure(None)) => true,
+            None => false,
+        };
+        if restart {
+            self.failures += 1;
+        }
+        restart
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::parser::args::CreateArgs;
+
+const API_SOCK_NAME: &str = "api.sock";
+const PID_FILE_NAME: &str = "dbs-cli.pid";
+/// The parent of the default runtime directories when dbs-cli runs as root.
+const ROOT_RUNTIME_BASE_DIR: &str = "/run/dbs-cli";
+
+/// The files dbs-cli created, removed on exit.
+static RUNTIME_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// The runtime directory, and whether dbs-cli created it, in which case it is
+/// removed on exit once its files are, if nothing else is left in it.
+static RUNTIME_DIR: Mutex<Option<(PathBuf, bool)>> = Mutex::new(None);
+/// The cleanups of the host resources dbs-cli set up, run on exit in the
+/// reverse order.
+#[allow(clippy::type_complexity)]
+static EXIT_HOOKS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+/// Remove `path` on exit.
+pub fn register(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    let mut files = RUNTIME_FILES.lock().unwrap();
+    // the sockets are registered again by each restart of the VM.
+    if !files.iter().any(|file| file == path) {
+        files.push(path.to_path_buf());
+    }
+}
+
+/// Run `hook` on exit, including the exits which do not return from
+/// `run_with_cli`, such as the shutdown timeout.
+pub fn on_exit(hook: impl FnOnce() + Send + 'static) {
+    EXIT_HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+/// The runtime directory of the VM, once set up.
+pub fn path() -> Option<PathBuf> {
+    RUNTIME_DIR
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|(dir, _)| dir.clone())
+}
+
+/// Remove a socket left by a previous run, so that it can be bound again.
+///
+/// A socket somebody still listens on is in use by another process, which is
+/// an error rather than a stale socket.
+pub fn remove_stale_socket(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(());
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!(
+            "socket {} is in use by another process",
+            path.display()
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the stale socket {}", path.display())),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to check whether {} is stale", path.display()))
+        }
+    }
+}
+
+/// Run the exit hooks, and remove the files dbs-cli created and the runtime
+/// directory.
+pub fn cleanup() {
+    let hooks = std::mem::take(&mut *EXIT_HOOKS.lock().unwrap());
+    for hook in hooks.into_iter().rev() {
+        hook();
+    }
+    for path in RUNTIME_FILES.lock().unwrap().drain(..) {
+        let _ = std::fs::remove_file(path);
+    }
+    // the files of others and the logs are kept, with the directory then.
+    if let Some((dir, true)) = RUNTIME_DIR.lock().unwrap().take() {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+/// Clean up and exit at once, from a thread other than the main one.
+pub fn exit(code: i32) -> ! {
+    cleanup();
+    std::process::exit(code);
+}
+
+/// Clean up on drop, which covers the errors returned by dbs-cli.
+pub struct CleanupGuard;
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        cleanup();
+    }
+}
+
+/// The runtime directory of a VM without `--runtime-dir`, named after the VM
+/// or the pid of dbs-cli.
+fn default_dir(create_args: &CreateArgs) -> PathBuf {
+    let base = if nix::unistd::geteuid().is_root() {
+        PathBuf::from(ROOT_RUNTIME_BASE_DIR)
+    } else if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        Path::new(&dir).join("dbs-cli")
+    } else {
+        std::env::temp_dir().join(format!("dbs-cli-{}", nix::unistd::geteuid()))
+    };
+    match &create_args.name {
+        Some(name) => base.join(name),
+        None => base.join(format!("dbs-cli-{}", std::process::id())),
+    }
+}
+
+/// Place the relative paths of the sockets, the pid file and the generated
+/// images of the VM in its runtime directory, `--runtime-dir` or a directory
+/// per VM, which is created if needed. The API socket and the pid file are
+/// created there by default, and these files are removed on exit. The logs
+/// given by relative paths are placed there only with `--runtime-dir`, and
+/// are kept. Returns the API socket path.
+pub fn setup(create_args: &mut CreateArgs, api_sock_path: &str) -> Result<String> {
+    let dir = match &create_args.runtime_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => default_dir(create_args),
+    };
+    let created = !dir.exists();
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create the runtime directory {}", dir.display()))?;
+    *RUNTIME_DIR.lock().unwrap() = Some((dir.clone(), created));
+
+    let in_dir = |path: &str| -> String {
+        if Path::new(path).is_absolute() {
+            path.to_string()
+        } else {
+            dir.join(path).to_string_lossy().into_owned()
+        }
+    };
+
+    if create_args.serial_path != "stdio" {
+        create_args.serial_path = in_dir(&create_args.serial_path);
+    }
+    if !create_args.vsock.is_empty() {
+        create_args.vsock = in_dir(&create_args.vsock);
+    }
+    // the logs of a VM without --runtime-dir stay in the working directory,
+    // as they are kept after it stops.
+    if create_args.runtime_dir.is_some() {
+        create_args.log_file = in_dir(&create_args.log_file);
+        create_args.dmesg_file = create_args.dmesg_file.as_deref().map(in_dir);
+        create_args.console_log.console_log_file = create_args
+            .console_log
+            .console_log_file
+            .as_deref()
+            .map(in_dir);
+        create_args.guest_panic.panic_dump_file = in_dir(&create_args.guest_panic.panic_dump_file);
+    }
+    create_args.net_config_disk = in_dir(&create_args.net_config_disk);
+    create_args.daemon.pid_file = Some(in_dir(
+        create_args
+            .daemon
+            .pid_file
+            .as_deref()
+            .unwrap_or(PID_FILE_NAME),
+    ));
+    // the guest network config disk is generated for each run.
+    register(&create_args.net_config_disk);
+
+    Ok(in_dir(if api_sock_path.is_empty() {
+        API_SOCK_NAME
+    } else {
+        api_sock_path
+    }))
+}
@@ -0,0 +1,306 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+
+use anyhow::{anyhow, Context, Result};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule, TargetArch,
+};
+
+use crate::parser::args::{SeccompArgs, SeccompLevel};
+
+/// The syscalls of the VMM thread, which runs the event loop, the devices
+/// and the threads they spawn. Running programs, forking and signalling
+/// other processes are left out: the restarts and the cleanup on exit,
+/// which runs `ip` and `tc`, are done by the main thread, which is not
+/// filtered.
+const VMM_SYSCALLS: &[i64] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_connect,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_faccessat,
+    libc::SYS_fallocate,
+    libc::SYS_fcntl,
+    libc::SYS_fdatasync,
+    libc::SYS_fstat,
+    libc::SYS_fstatfs,
+    libc::SYS_fsync,
+    libc::SYS_ftruncate,
+    libc::SYS_futex,
+    libc::SYS_getcwd,
+    libc::SYS_getdents64,
+    libc::SYS_getegid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getpeername,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_gettid,
+    libc::SYS_gettimeofday,
+    libc::SYS_getuid,
+    libc::SYS_io_destroy,
+    libc::SYS_io_getevents,
+    libc::SYS_io_setup,
+    libc::SYS_io_submit,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_io_uring_setup,
+    libc::SYS_ioctl,
+    libc::SYS_listen,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_memfd_create,
+    libc::SYS_mkdirat,
+    libc::SYS_mlock,
+    libc::SYS_mlockall,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munlock,
+    libc::SYS_munlockall,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_prctl,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_prlimit64,
+    libc::SYS_pselect6,
+    libc::SYS_pwrite64,
+    libc::SYS_pwritev,
+    libc::SYS_read,
+    libc::SYS_readlinkat,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_renameat,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_statx,
+    libc::SYS_sysinfo,
+    libc::SYS_tgkill,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_gettime,
+    libc::SYS_timerfd_settime,
+    libc::SYS_umask,
+    libc::SYS_uname,
+    libc::SYS_unlinkat,
+    libc::SYS_write,
+    libc::SYS_writev,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+/// The syscalls of a vCPU thread, which runs the guest and emulates the
+/// MMIO and PIO accesses, including the activation of the virtio devices.
+const VCPU_SYSCALLS: &[i64] = &[
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_epoll_ctl,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_write,
+    libc::SYS_writev,
+];
+
+/// The ioctl types of KVM, vhost and VFIO.
+const KVMIO: u64 = 0xae;
+const VHOST_VIRTIO: u64 = 0xaf;
+const VFIO_TYPE: u64 = 0x3b;
+/// The ioctl types of tun, the terminal and the socket ioctls on the tap
+/// devices, and of the block devices.
+const TTY_TYPE: u64 = 0x54;
+const SOCK_IOC_TYPE: u64 = 0x89;
+const BLOCK_TYPE: u64 = 0x12;
+
+/// Build the seccomp filters of the VMM and vCPU threads, from
+/// `--seccomp-filter` or the built-in ones of `--seccomp-level`.
+///
+/// Dragonball applies the VMM filter to the thread running the event loop
+/// once the VM has started, so the threads spawned by dbs-cli before, i.e.
+/// the main thread, the api server and the consoles, are not filtered.
+pub fn build_filters(args: &SeccompArgs) -> Result<(BpfProgram, BpfProgram)> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH)
+        .map_err(|err| anyhow!("seccomp is not supported: {err}"))?;
+
+    if let Some(path) = &args.seccomp_filter {
+        if args.seccomp_audit {
+            return Err(anyhow!(
+                "--seccomp-audit applies to the built-in filters, set the actions in {path} instead"
+            ));
+        }
+        return load_filters(path, arch);
+    }
+
+    // the denied syscalls kill dbs-cli with SIGSYS, or are logged in audit mode.
+    let mismatch_action = if args.seccomp_audit {
+        println!("Warning: seccomp audit mode, the syscalls the filters would deny are only logged to the kernel audit log.");
+        SeccompAction::Log
+    } else {
+        SeccompAction::Trap
+    };
+    let (vmm_rules, vcpu_rules) = match args.seccomp_level {
+        SeccompLevel::None => return Ok((vec![], vec![])),
+        SeccompLevel::Basic => (
+            allow_syscalls(VMM_SYSCALLS, None)?,
+            allow_syscalls(VMM_SYSCALLS, None)?,
+        ),
+        SeccompLevel::Strict => (
+            allow_syscalls(
+                VMM_SYSCALLS,
+                Some(&[
+                    KVMIO,
+                    VHOST_VIRTIO,
+                    VFIO_TYPE,
+                    TTY_TYPE,
+                    SOCK_IOC_TYPE,
+                    BLOCK_TYPE,
+                ]),
+            )?,
+            allow_syscalls(VCPU_SYSCALLS, Some(&[KVMIO, VHOST_VIRTIO, VFIO_TYPE]))?,
+        ),
+    };
+
+    let compile = |rules, thread: &str| -> Result<BpfProgram> {
+        let filter = SeccompFilter::new(rules, mismatch_action.clone(), SeccompAction::Allow, arch)
+            .with_context(|| format!("Invalid seccomp filter of the {thread} thread"))?;
+        BpfProgram::try_from(filter)
+            .with_context(|| format!("Failed to compile the seccomp filter of the {thread} thread"))
+    };
+    Ok((compile(vmm_rules, "VMM")?, compile(vcpu_rules, "vCPU")?))
+}
+
+/// The rules allowing `syscalls`, with the ioctls limited to `ioctl_types`.
+fn allow_syscalls(
+    syscalls: &[i64],
+    ioctl_types: Option<&[u64]>,
+) -> Result<BTreeMap<i64, Vec<SeccompRule>>> {
+    let mut rules = BTreeMap::new();
+    for &syscall in syscalls {
+        let syscall_rules = match ioctl_types {
+            Some(types) if syscall == libc::SYS_ioctl => types
+                .iter()
+                .map(|ioctl_type| {
+                    // the type of an ioctl request is its second byte.
+                    let condition = SeccompCondition::new(
+                        1,
+                        SeccompCmpArgLen::Dword,
+                        SeccompCmpOp::MaskedEq(0xff00),
+                        ioctl_type << 8,
+                    )?;
+                    SeccompRule::new(vec![condition])
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![],
+        };
+        rules.insert(syscall, syscall_rules);
+    }
+    Ok(rules)
+}
+
+/// Load the "vmm" and "vcpu" filters of a seccompiler JSON file.
+fn load_filters(path: &str, arch: TargetArch) -> Result<(BpfProgram, BpfProgram)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    let mut filters = seccompiler::compile_from_json(file, arch)
+        .with_context(|| format!("Failed to compile the seccomp filters in {path}"))?;
+    let mut take = |thread: &str| {
+        filters
+            .remove(thread)
+            .ok_or_else(|| anyhow!("{path} has no {thread:?} filter"))
+    };
+    Ok((take("vmm")?, take("vcpu")?))
+}
@@ -0,0 +1,171 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use dragonball::api::v1::{InstanceInfo, VmmRequest, VmmResponse};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::parser::args::CreateArgs;
+use crate::restart::{self, HotplugRecord};
+use crate::runtime_dir;
+use crate::utils;
+use crate::vmm_comm_trait::VMMComm;
+
+/// The signals handled by dbs-cli.
+pub fn handled_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in [
+        Signal::SIGTERM,
+        Signal::SIGINT,
+        Signal::SIGHUP,
+        Signal::SIGUSR1,
+    ] {
+        signals.add(signal);
+    }
+    signals
+}
+
+extern "C" fn ignore_signal(_signal: libc::c_int) {}
+
+/// Block the handled signals, which are then only received by the signal
+/// handler thread. This has to be called before any thread is spawned, as
+/// the threads inherit the signal mask.
+///
+/// A handler is set as well, as the init process of a PID namespace, e.g.
+/// dbs-cli in a jail, does not receive the signals it has no handler for.
+pub fn block_signals() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(ignore_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in handled_signals().iter() {
+        // SAFETY: the handler does nothing.
+        unsafe { sigaction(signal, &action) }
+            .with_context(|| format!("Failed to set the handler of {signal:?}"))?;
+    }
+    handled_signals()
+        .thread_block()
+        .context("Failed to block the signals")
+}
+
+/// Handle the signals on a thread of its own:
+/// - SIGTERM and SIGINT stop the VM at once, without a guest shutdown, and
+///   dbs-cli exits anyway after the stop timeout. Another one while stopping
+///   exits at once.
+/// - SIGHUP reopens the log files.
+/// - SIGUSR1 dumps the VM state and configuration to the log.
+pub struct SignalHandler {
+    pub vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+    pub to_vmm: Option<Sender<VmmRequest>>,
+    pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+    pub to_vmm_fd: EventFd,
+    pub create_args: CreateArgs,
+    pub hotplug_record: HotplugRecord,
+}
+
+impl VMMComm for SignalHandler {
+    fn get_to_vmm(&self) -> Option<&Sender<VmmRequest>> {
+        self.to_vmm.as_ref()
+    }
+
+    fn get_from_vmm(&self) -> Option<Arc<Mutex<Receiver<VmmResponse>>>> {
+        self.from_vmm.clone()
+    }
+
+    fn get_to_vmm_fd(&self) -> &EventFd {
+        &self.to_vmm_fd
+    }
+}
+
+impl SignalHandler {
+    pub fn new(
+        vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+        to_vmm: Option<Sender<VmmRequest>>,
+        from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+        to_vmm_fd: EventFd,
+        create_args: CreateArgs,
+        hotplug_record: HotplugRecord,
+    ) -> Self {
+        SignalHandler {
+            vmm_shared_info,
+            to_vmm,
+            from_vmm,
+            to_vmm_fd,
+            create_args,
+            hotplug_record,
+        }
+    }
+
+    pub fn run_signal_handler(&self) -> Result<()> {
+        let signals = handled_signals();
+        let mut stopping = false;
+        loop {
+            let signal = signals.wait().context("Failed to wait for signals")?;
+            match signal {
+                Signal::SIGTERM | Signal::SIGINT if stopping => {
+                    log::warn!("received {:?} again, exiting", signal);
+                    runtime_dir::exit(1);
+                }
+                Signal::SIGTERM | Signal::SIGINT => {
+                    stopping = true;
+                    log::warn!("received {:?}, stopping the VM", signal);
+                    self.stop();
+                }
+                Signal::SIGHUP => {
+                    if let Err(err) = utils::reopen_log_files() {
+                        log::warn!("Failed to reopen the log files: {:?}", err);
+                    }
+                }
+                Signal::SIGUSR1 => self.dump_state(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Stop the VM immediately, and exit if the VMM takes longer than the
+    /// stop timeout.
+    ///
+    /// The Dragonball API has no action to press the ACPI power button or
+    /// send Ctrl-Alt-Del to the guest, so the VM is stopped the way the
+    /// `shutdown` command does, without the guest shutting down first.
+    fn stop(&self) {
+        let timeout = Duration::from_secs(self.create_args.daemon.stop_timeout);
+        thread::Builder::new()
+            .name("stop_timer".to_owned())
+            .spawn(move || {
+                thread::sleep(timeout);
+                log::warn!("the VM did not stop in {:?}, exiting", timeout);
+                runtime_dir::exit(1);
+            })
+            .unwrap();
+
+        restart::request_stop();
+        if let Err(err) = self.shutdown_microvm() {
+            log::warn!("Failed to stop the VM: {:?}", err);
+        }
+    }
+
+    fn dump_state(&self) {
+        let instance_info = self.vmm_shared_info.read().unwrap().clone();
+        let vm_config = self
+            .get_vm_configuration()
+            .map(|vm_config| format!("{vm_config:?}"))
+            .unwrap_or_else(|err| format!("{err:?}"));
+        let create_args = serde_json::to_string(&self.create_args).unwrap_or_default();
+        let hotplugged = serde_json::to_string(&self.hotplug_record.requests()).unwrap_or_default();
+        slog::warn!(slog_scope::logger(), "VM state";
+            "event" => "vm_state",
+            "vm_id" => &instance_info.id,
+            "state" => format!("{:?}", instance_info.state),
+            "vm_config" => vm_config,
+            "create_args" => create_args,
+            "hotplugged" => hotplugged);
+    }
+}
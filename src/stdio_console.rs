@@ -0,0 +1,209 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use dragonball::api::v1::{InstanceInfo, VmmRequest, VmmResponse};
+use nix::poll::{poll, PollFd, PollFlags};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::console_log::ConsoleLog;
+use crate::restart;
+use crate::runtime_dir;
+use crate::vmm_comm_trait::VMMComm;
+
+/// Ctrl-A, the prefix of the console commands.
+const ESCAPE_KEY: u8 = 0x01;
+const CONSOLE_BUF_SIZE: usize = 4096;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// The name of the serial console socket in the runtime directory.
+const VMM_SOCK_NAME: &str = "stdio-console.vmm";
+
+const HELP: &str = "console commands:
+C-a h    print this help
+C-a x    stop the VM
+C-a s    print the VM status
+C-a C-a  send C-a to the guest
+There is no command to pause the VM, Dragonball has no API to pause the vCPUs.";
+
+/// The socket the Dragonball serial console listens on for the stdio console,
+/// in the runtime directory of the VM.
+pub fn vmm_sock_path() -> Result<String> {
+    let dir = runtime_dir::path()
+        .ok_or_else(|| anyhow!("no runtime directory for the stdio console socket"))?;
+    Ok(dir.join(VMM_SOCK_NAME).to_string_lossy().into_owned())
+}
+
+/// The console on the stdio of dbs-cli.
+///
+/// dbs-cli relays between its stdio and the Dragonball serial console, so that
+/// it can take QEMU-like commands prefixed by Ctrl-A.
+pub struct StdioConsole {
+    pub vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+    pub to_vmm: Option<Sender<VmmRequest>>,
+    pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+    pub to_vmm_fd: EventFd,
+    pub console_log: ConsoleLog,
+}
+
+impl VMMComm for StdioConsole {
+    fn get_to_vmm(&self) -> Option<&Sender<VmmRequest>> {
+        self.to_vmm.as_ref()
+    }
+
+    fn get_from_vmm(&self) -> Option<Arc<Mutex<Receiver<VmmResponse>>>> {
+        self.from_vmm.clone()
+    }
+
+    fn get_to_vmm_fd(&self) -> &EventFd {
+        &self.to_vmm_fd
+    }
+}
+
+impl StdioConsole {
+    pub fn new(
+        vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+        to_vmm: Option<Sender<VmmRequest>>,
+        from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
+        to_vmm_fd: EventFd,
+        console_log: ConsoleLog,
+    ) -> Self {
+        StdioConsole {
+            vmm_shared_info,
+            to_vmm,
+            from_vmm,
+            to_vmm_fd,
+            console_log,
+        }
+    }
+
+    pub fn run_stdio_console(&self, sock_path: String) -> Result<()> {
+        let mut stdin_open = true;
+        // the console stays on stdio for a restarted VM.
+        loop {
+            // the serial console socket is created when the VM starts.
+            let vmm = loop {
+                match UnixStream::connect(&sock_path) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(CONNECT_RETRY_INTERVAL),
+                }
+            };
+            if stdin_open {
+                stdin_open = self.attach(vmm)?;
+            } else {
+                self.relay_output(vmm)?;
+            }
+        }
+    }
+
+    /// Relay between stdio and the VM until the VM is gone, returns whether
+    /// stdin is still open.
+    fn attach(&self, mut vmm: UnixStream) -> Result<bool> {
+        let stdin_fd = std::io::stdin().as_raw_fd();
+        let mut stdout = std::io::stdout();
+        let mut buf = [0u8; CONSOLE_BUF_SIZE];
+        let mut escape_pending = false;
+        loop {
+            let mut fds = [
+                PollFd::new(vmm.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(stdin_fd, PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, -1) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                result => result.context("Failed to poll the stdio console")?,
+            };
+
+            if !fds[0].revents().unwrap_or_else(PollFlags::empty).is_empty() {
+                let count = vmm.read(&mut buf).unwrap_or(0);
+                if count == 0 {
+                    return Ok(true);
+                }
+                self.console_log.record(&buf[..count]);
+                stdout.write_all(&buf[..count])?;
+                stdout.flush()?;
+            }
+
+            if !fds[1].revents().unwrap_or_else(PollFlags::empty).is_empty() {
+                let count = read_stdin(stdin_fd, &mut buf)?;
+                if count == 0 {
+                    // keep the VM running without a console input.
+                    self.relay_output(vmm)?;
+                    return Ok(false);
+                }
+                let mut input = Vec::with_capacity(count);
+                for &byte in buf[..count].iter() {
+                    if escape_pending {
+                        escape_pending = false;
+                        match byte {
+                            ESCAPE_KEY => input.push(ESCAPE_KEY),
+                            command => self.run_command(command),
+                        }
+                    } else if byte == ESCAPE_KEY {
+                        escape_pending = true;
+                    } else {
+                        input.push(byte);
+                    }
+                }
+                if vmm.write_all(&input).is_err() {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    fn relay_output(&self, mut vmm: UnixStream) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        let mut buf = [0u8; CONSOLE_BUF_SIZE];
+        loop {
+            let count = vmm.read(&mut buf).unwrap_or(0);
+            if count == 0 {
+                return Ok(());
+            }
+            self.console_log.record(&buf[..count]);
+            stdout.write_all(&buf[..count])?;
+            stdout.flush()?;
+        }
+    }
+
+    fn run_command(&self, command: u8) {
+        let message = match command {
+            b'x' => {
+                // a VM stopped on purpose is not restarted.
+                restart::request_stop();
+                match self.shutdown_microvm() {
+                    Ok(_) => "stopping the VM".to_string(),
+                    Err(err) => format!("failed to stop the VM: {err:?}"),
+                }
+            }
+            b's' => self.status(),
+            b'h' => HELP.to_string(),
+            b'p' => "pausing the VM is not supported".to_string(),
+            _ => return,
+        };
+        print!("\r\ndbs-cli: {}\r\n", message.replace('\n', "\r\n"));
+        let _ = std::io::stdout().flush();
+    }
+
+    fn status(&self) -> String {
+        let state = self.vmm_shared_info.read().unwrap().state.clone();
+        match self.get_vm_configuration() {
+            Ok(vm_config) => format!(
+                "VM state: {:?}, vcpus: {}/{}, memory: {} MiB",
+                state, vm_config.vcpu_count, vm_config.max_vcpu_count, vm_config.mem_size_mib
+            ),
+            Err(_) => format!("VM state: {state:?}"),
+        }
+    }
+}
+
+/// Read stdin unbuffered, as buffered input would not wake up poll.
+fn read_stdin(stdin_fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+    nix::unistd::read(stdin_fd, buf).context("Failed to read stdin")
+}
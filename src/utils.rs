@@ -3,24 +3,90 @@ use slog::Drain;
 use slog::*;
 use slog_scope::set_global_logger;
 
+use crate::dmesg::DmesgDrain;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+
+/// A log file which is reopened on SIGHUP, after it has been rotated.
+#[derive(Clone)]
+pub(crate) struct LogFile {
+    path: String,
+    file: Arc<Mutex<File>>,
+}
+
+/// The log files to reopen.
+static LOG_FILES: Mutex<Vec<LogFile>> = Mutex::new(Vec::new());
+
+impl LogFile {
+    fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .truncate(true)
+            .read(true)
+            .create(true)
+            .write(true)
+            .open(path)?;
+        let log_file = LogFile {
+            path: path.to_string(),
+            file: Arc::new(Mutex::new(file)),
+        };
+        LOG_FILES.lock().unwrap().push(log_file.clone());
+        Ok(log_file)
+    }
+
+    /// Open the file at the path again, appending to it if it still exists.
+    fn reopen(&self) -> std::io::Result<()> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+/// Reopen the log file and the dmesg file, e.g. for logrotate.
+pub fn reopen_log_files() -> anyhow::Result<()> {
+    for log_file in LOG_FILES.lock().unwrap().iter() {
+        log_file
+            .reopen()
+            .with_context(|| format!("Failed to reopen the log file {}", log_file.path))?;
+    }
+    Ok(())
+}
 
-pub fn setup_db_log(log_file_path: &String, log_level: &str) {
+pub fn setup_db_log(log_file_path: &str, log_level: &str, dmesg_file_path: &Option<String>) {
     let log_level = Level::from_str(log_level).unwrap();
 
-    let file = std::fs::OpenOptions::new()
-        .truncate(true)
-        .read(true)
-        .create(true)
-        .write(true)
-        .open(log_file_path)
-        .expect("Cannot write to the log file.");
+    let file = LogFile::open(log_file_path).expect("Cannot write to the log file.");
 
-    let root = slog::Logger::root(
-        Mutex::new(slog_json::Json::default(file).filter_level(log_level)).map(slog::Fuse),
-        o!("version" => env!("CARGO_PKG_VERSION")),
-    );
+    let vmm_log =
+        Mutex::new(slog_json::Json::default(file).filter_level(log_level)).map(slog::Fuse);
+    let root = match dmesg_file_path {
+        Some(dmesg_file_path) => {
+            let dmesg_file =
+                LogFile::open(dmesg_file_path).expect("Cannot write to the dmesg file.");
+            slog::Logger::root(
+                DmesgDrain::new(vmm_log, dmesg_file),
+                o!("version" => env!("CARGO_PKG_VERSION")),
+            )
+        }
+        None => slog::Logger::root(vmm_log, o!("version" => env!("CARGO_PKG_VERSION"))),
+    };
 
     let guard = set_global_logger(root);
     guard.cancel_reset();
@@ -42,3 +108,207 @@ pub(crate) fn net_device_name(config: &NetworkInterfaceConfig) -> String {
         }
     }
 }
+
+/// Open /dev/kvm, once per `create`, for the checks of the host and the VMM.
+pub(crate) fn open_kvm() -> anyhow::Result<kvm_ioctls::Kvm> {
+    kvm_ioctls::Kvm::new()
+        .context("Failed to open /dev/kvm, run dbs-cli check-host to check the host")
+}
+
+/// Get the host tap device name from `NetworkInterfaceConfig`, if the backend uses one.
+pub(crate) fn net_tap_name(config: &NetworkInterfaceConfig) -> Option<&str> {
+    match &config.backend {
+        dragonball::api::v1::Backend::Virtio(config)
+        | dragonball::api::v1::Backend::Vhost(config) => Some(config.host_dev_name.as_str()),
+        dragonball::api::v1::Backend::VhostUser(_) => None,
+    }
+}
+
+/// Check whether the kernel command line already has a `key=` parameter.
+pub(crate) fn has_kernel_param(boot_args: &str, key: &str) -> bool {
+    kernel_params(boot_args).any(|param| param.split_once('=').map_or(param, |(k, _)| k) == key)
+}
+
+/// Split the kernel command line at the first `--`, after which the
+/// parameters are passed to init.
+fn split_init_args(boot_args: &str) -> (&str, Option<&str>) {
+    let is_separator = |c: Option<char>| c.is_none_or(char::is_whitespace);
+    for (index, _) in boot_args.match_indices("--") {
+        if is_separator(boot_args[..index].chars().next_back())
+            && is_separator(boot_args[index + 2..].chars().next())
+        {
+            return (
+                boot_args[..index].trim_end(),
+                Some(boot_args[index + 2..].trim_start()),
+            );
+        }
+    }
+    (boot_args, None)
+}
+
+/// Join the kernel parameters and the init parameters split off by
+/// `split_init_args`.
+fn join_init_args(kernel: &str, init: Option<&str>) -> String {
+    match init {
+        Some(init) => format!("{kernel} -- {init}").trim().to_string(),
+        None => kernel.to_string(),
+    }
+}
+
+/// Append a parameter to the kernel command line. Parameters following `--`
+/// are passed to init, so the new parameter goes in front of them.
+pub(crate) fn append_kernel_param(boot_args: &str, param: &str) -> String {
+    let (kernel, init) = split_init_args(boot_args);
+    let kernel = match kernel.trim() {
+        "" => param.to_string(),
+        kernel => format!("{kernel} {param}"),
+    };
+    join_init_args(&kernel, init)
+}
+
+/// Set `key=value` on the kernel command line, replacing any `key=` parameter.
+pub(crate) fn set_kernel_param(boot_args: &str, key: &str, value: &str) -> String {
+    let (kernel, init) = split_init_args(boot_args);
+    let mut params: Vec<String> = kernel
+        .split_whitespace()
+        .filter(|param| param.split_once('=').map_or(*param, |(k, _)| k) != key)
+        .map(str::to_string)
+        .collect();
+    params.push(format!("{key}={value}"));
+    join_init_args(&params.join(" "), init)
+}
+
+/// Put a parameter first on the kernel command line. For `console=`, the last
+/// one is the `/dev/console` of the guest, which this keeps unchanged.
+pub(crate) fn prepend_kernel_param(boot_args: &str, param: &str) -> String {
+    format!("{} {}", param, boot_args.trim_start())
+        .trim_end()
+        .to_string()
+}
+
+/// Check whether the kernel command line has the exact parameter `param`.
+pub(crate) fn has_kernel_param_value(boot_args: &str, param: &str) -> bool {
+    kernel_params(boot_args).any(|p| p == param)
+}
+
+fn kernel_params(boot_args: &str) -> impl Iterator<Item = &str> {
+    split_init_args(boot_args).0.split_whitespace()
+}
+
+/// Format the current time as an RFC 3339 UTC timestamp with microseconds.
+pub(crate) fn utc_timestamp() -> String {
+    format_utc_timestamp(chrono::Utc::now())
+}
+
+fn format_utc_timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+/// The thread name prefix of the vCPU threads of Dragonball, followed by
+/// the vCPU index.
+const VCPU_THREAD_PREFIX: &str = "db_vcpu";
+
+/// How long to wait for the threads of the vCPUs being started or hotplugged.
+const VCPU_THREAD_RETRIES: u32 = 20;
+const VCPU_THREAD_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// The vCPU threads of the running VM, as (vCPU index, thread ID).
+pub fn vcpu_threads() -> Vec<(u32, i32)> {
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            log::warn!("Failed to list the threads: {:?}", err);
+            return vec![];
+        }
+    };
+    let mut threads: Vec<(u32, i32)> = tasks
+        .filter_map(|task| {
+            let task = task.ok()?;
+            let tid = task.file_name().to_str()?.parse().ok()?;
+            let comm = std::fs::read_to_string(task.path().join("comm")).ok()?;
+            let index = comm
+                .trim_end()
+                .strip_prefix(VCPU_THREAD_PREFIX)?
+                .parse()
+                .ok()?;
+            Some((index, tid))
+        })
+        .collect();
+    threads.sort_unstable();
+    threads
+}
+
+/// The vCPU threads, once there are `vcpu_count` of them or after a while,
+/// as the threads of the vCPUs being hotplugged start in the background.
+pub fn wait_vcpu_threads(vcpu_count: usize) -> Vec<(u32, i32)> {
+    for _ in 1..VCPU_THREAD_RETRIES {
+        let threads = vcpu_threads();
+        if threads.len() >= vcpu_count {
+            return threads;
+        }
+        std::thread::sleep(VCPU_THREAD_RETRY_INTERVAL);
+    }
+    let threads = vcpu_threads();
+    if threads.len() < vcpu_count {
+        log::warn!("found {} of the {} vCPU threads", threads.len(), vcpu_count);
+    }
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc_timestamp() {
+        let time = chrono::DateTime::from_timestamp(951_782_400, 123_456_789).unwrap();
+        assert_eq!(format_utc_timestamp(time), "2000-02-29T00:00:00.123456Z");
+        let time = chrono::DateTime::from_timestamp(0, 0).unwrap();
+        assert_eq!(format_utc_timestamp(time), "1970-01-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn test_append_kernel_param() {
+        assert_eq!(append_kernel_param("", "ip=off"), "ip=off");
+        assert_eq!(
+            append_kernel_param("console=ttyS0 ", "ip=off"),
+            "console=ttyS0 ip=off"
+        );
+        assert_eq!(
+            append_kernel_param("console=ttyS0 -- init_arg", "ip=off"),
+            "console=ttyS0 ip=off -- init_arg"
+        );
+        assert_eq!(
+            append_kernel_param("console=ttyS0 --", "ip=off"),
+            "console=ttyS0 ip=off --"
+        );
+        assert_eq!(
+            append_kernel_param("-- init_arg", "ip=off"),
+            "ip=off -- init_arg"
+        );
+        // only a whole `--` separates the init parameters.
+        assert_eq!(
+            append_kernel_param("opt=a--b --x", "ip=off"),
+            "opt=a--b --x ip=off"
+        );
+    }
+
+    #[test]
+    fn test_set_kernel_param() {
+        assert_eq!(
+            set_kernel_param("panic=1 console=ttyS0 -- panic=5", "panic", "0"),
+            "console=ttyS0 panic=0 -- panic=5"
+        );
+        assert_eq!(
+            set_kernel_param("console=ttyS0 --", "panic", "0"),
+            "console=ttyS0 panic=0 --"
+        );
+    }
+
+    #[test]
+    fn test_has_kernel_param() {
+        assert!(has_kernel_param("console=ttyS0 ip=off", "ip"));
+        assert!(!has_kernel_param("console=ttyS0 -- ip=off", "ip"));
+        assert!(!has_kernel_param("console=ttyS0 --\tip=off", "ip"));
+    }
+}
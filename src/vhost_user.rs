@@ -0,0 +1,80 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+
+use dragonball::device_manager::blk_dev_mgr::{BlockDeviceConfigInfo, BlockDeviceType};
+use dragonball::device_manager::fs_dev_mgr::FsDeviceConfigInfo;
+use serde_derive::{Deserialize, Serialize};
+
+/// The mode of `FsDeviceConfigInfo` for an external virtio-fs daemon.
+const FS_MODE_VHOST_USER: &str = "vhostuser";
+
+fn default_num_queues() -> usize {
+    1
+}
+
+fn default_blk_queue_size() -> u16 {
+    128
+}
+
+fn default_fs_queue_size() -> u16 {
+    1024
+}
+
+/// A vhost-user-blk device backed by an external target, e.g. SPDK.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VhostUserBlkConfig {
+    pub drive_id: String,
+    /// The vhost-user socket of the target
+    pub sock_path: String,
+    #[serde(default)]
+    pub is_root_device: bool,
+    #[serde(default)]
+    pub is_read_only: bool,
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_blk_queue_size")]
+    pub queue_size: u16,
+}
+
+impl From<VhostUserBlkConfig> for BlockDeviceConfigInfo {
+    fn from(config: VhostUserBlkConfig) -> Self {
+        BlockDeviceConfigInfo {
+            drive_id: config.drive_id,
+            device_type: BlockDeviceType::Spdk,
+            path_on_host: PathBuf::from(config.sock_path),
+            is_root_device: config.is_root_device,
+            is_read_only: config.is_read_only,
+            num_queues: config.num_queues,
+            queue_size: config.queue_size,
+            ..BlockDeviceConfigInfo::default()
+        }
+    }
+}
+
+/// A vhost-user-fs device backed by an external daemon, e.g. virtiofsd.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VhostUserFsConfig {
+    /// The mount tag of the file system in the guest
+    pub tag: String,
+    /// The vhost-user socket of the daemon
+    pub sock_path: String,
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_fs_queue_size")]
+    pub queue_size: u16,
+}
+
+impl From<VhostUserFsConfig> for FsDeviceConfigInfo {
+    fn from(config: VhostUserFsConfig) -> Self {
+        FsDeviceConfigInfo {
+            mode: FS_MODE_VHOST_USER.to_string(),
+            tag: config.tag,
+            sock_path: config.sock_path,
+            num_queues: config.num_queues,
+            queue_size: config.queue_size,
+            ..FsDeviceConfigInfo::default()
+        }
+    }
+}
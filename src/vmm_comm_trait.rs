@@ -38,6 +38,14 @@ pub trait VMMComm {
     }
 
     fn send_request(&self, vmm_action: VmmAction) -> Result<VmmResponse> {
+        let from_vmm = self
+            .get_from_vmm()
+            .ok_or_else(|| anyhow!("from_vmm is None"))?;
+        // the API server, the consoles, the signal handler and the panic
+        // handler share the channels, so the lock is held from sending the
+        // request to receiving its response, which no other thread takes.
+        let from_vmm = from_vmm.lock().unwrap();
+
         if let Some(to_vmm) = self.get_to_vmm() {
             to_vmm
                 .send(Box::new(vmm_action.clone()))
@@ -51,13 +59,9 @@ pub trait VMMComm {
             return Err(anyhow!("failed to notify vmm: {}", e));
         }
 
-        if let Some(from_vmm) = self.get_from_vmm().as_ref() {
-            match from_vmm.lock().unwrap().recv() {
-                Err(e) => Err(anyhow!("vmm recv err: {}", e)),
-                Ok(vmm_outcome) => Ok(vmm_outcome),
-            }
-        } else {
-            Err(anyhow!("from_vmm is None"))
+        match from_vmm.recv() {
+            Err(e) => Err(anyhow!("vmm recv err: {}", e)),
+            Ok(vmm_outcome) => Ok(vmm_outcome),
         }
     }
     fn handle_request_with_retry(&self, req: Request) -> Result<VmmData> {
@@ -102,6 +106,22 @@ pub trait VMMComm {
         Ok(())
     }
 
+    fn shutdown_microvm(&self) -> Result<()> {
+        self.handle_request(Request::Sync(VmmAction::ShutdownMicroVm))
+            .context("Failed to shutdown MicroVm")?;
+        Ok(())
+    }
+
+    fn get_vm_configuration(&self) -> Result<VmConfigInfo> {
+        match self
+            .handle_request(Request::Sync(VmmAction::GetVmConfiguration))
+            .context("Failed to get vm configuration")?
+        {
+            VmmData::MachineConfiguration(vm_config) => Ok(*vm_config),
+            vmm_data => Err(anyhow!("unexpected vmm data {:?}", vmm_data)),
+        }
+    }
+
     fn insert_block_device(&self, device_cfg: BlockDeviceConfigInfo) -> Result<()> {
         self.handle_request_with_retry(Request::Sync(VmmAction::InsertBlockDevice(
             device_cfg.clone(),
@@ -153,6 +173,14 @@ pub trait VMMComm {
         Ok(())
     }
 
+    fn insert_vhost_user_blk(&self, blk_cfg: BlockDeviceConfigInfo) -> Result<()> {
+        self.handle_request_with_retry(Request::Sync(VmmAction::InsertBlockDevice(
+            blk_cfg.clone(),
+        )))
+        .with_context(|| format!("Failed to insert vhost-user-blk device {:?}", blk_cfg))?;
+        Ok(())
+    }
+
     fn insert_fs(&self, fs_cfg: FsDeviceConfigInfo) -> Result<()> {
         self.handle_request(Request::Sync(VmmAction::InsertFsDevice(fs_cfg.clone())))
             .with_context(|| format!("Failed to insert {} fs device {:?}", fs_cfg.mode, fs_cfg))?;